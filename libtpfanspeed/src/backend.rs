@@ -0,0 +1,564 @@
+use crate::{
+    error::*, get_fan_status_from_proc_output, hwmon, FanReading, FanSpeed, FanStatus,
+    FAN_WATCHDOG_PATH, MAX_WATCHDOG_TIMEOUT, PROC_FAN_PATH,
+};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+/// Something that can read and control a ThinkPad fan.
+///
+/// The free functions in the crate root (`set_fanspeed`, `get_rpm`, ...) use
+/// [`ProcfsBackend`]. Code that is generic over this trait can be exercised
+/// with [`MockBackend`] on machines without thinkpad_acpi.
+pub trait FanBackend {
+    fn status(&mut self) -> Result<FanStatus, Error>;
+
+    fn set_level(&mut self, fs: FanSpeed) -> Result<(), Error>;
+
+    /// Arm the firmware watchdog: if no command is sent for `timeout` seconds,
+    /// the fan goes back to `auto`. 0 disarms it.
+    fn set_watchdog(&mut self, timeout: u8) -> Result<(), Error>;
+
+    /// Turn the fan back on (at `auto`) after [`FanBackend::disable`].
+    fn enable(&mut self) -> Result<(), Error>;
+
+    /// Stop the fan. This is the same as level 0.
+    fn disable(&mut self) -> Result<(), Error>;
+
+    /// Set a PWM duty cycle (0-255), see [`FanSpeed::from_pwm`] for how it maps to levels.
+    fn set_pwm(&mut self, pwm: u8) -> Result<(), Error> {
+        self.set_level(FanSpeed::from_pwm(pwm))
+    }
+
+    fn rpm(&mut self) -> Result<u16, Error> {
+        Ok(self.status()?.speed)
+    }
+
+    /// Every fan with its speed. Most ThinkPads only have the one reported in [`FanStatus`].
+    fn fans(&mut self) -> Result<Vec<FanReading>, Error> {
+        Ok(vec![FanReading {
+            id: 1,
+            rpm: self.rpm()?,
+        }])
+    }
+
+    /// Set the level of a single fan.
+    ///
+    /// thinkpad_acpi drives every fan from the same level, so this only works
+    /// if `fan` is the only fan there is.
+    fn set_fan_level(&mut self, fan: u8, fs: FanSpeed) -> Result<(), Error> {
+        let fans = self.fans()?;
+
+        if !fans.iter().any(|f| f.id == fan) {
+            return Err(err!(
+                InvalidValue,
+                "Run `tpfanctl rpm` to see the available fans",
+                "Fan {} does not exist",
+                fan
+            ));
+        }
+
+        if fans.len() > 1 {
+            return Err(err!(
+                Unsupported,
+                "The firmware sets every fan to the same level, leave out the fan",
+                "Can't set the level of fan {} on its own",
+                fan
+            ));
+        }
+
+        self.set_level(fs)
+    }
+}
+
+/// Talks to thinkpad_acpi through `/proc/acpi/ibm/fan` (or another file with
+/// the same format).
+#[derive(Debug, Clone)]
+pub struct ProcfsBackend {
+    path: PathBuf,
+    hwmon: PathBuf,
+    watchdog: PathBuf,
+}
+
+impl Default for ProcfsBackend {
+    fn default() -> Self {
+        Self::new(PROC_FAN_PATH)
+    }
+}
+
+impl ProcfsBackend {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            hwmon: PathBuf::from(hwmon::HWMON_PATH),
+            watchdog: PathBuf::from(FAN_WATCHDOG_PATH),
+        }
+    }
+
+    /// Read the current watchdog timeout from `watchdog` instead of the thinkpad_hwmon driver.
+    pub fn with_watchdog_path(mut self, watchdog: impl Into<PathBuf>) -> Self {
+        self.watchdog = watchdog.into();
+        self
+    }
+
+    /// Look for the thinkpad hwmon device (which reports every fan) under `hwmon`.
+    pub fn with_hwmon(mut self, hwmon: impl Into<PathBuf>) -> Self {
+        self.hwmon = hwmon.into();
+        self
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    fn open(&self, write: bool) -> Result<File, Error> {
+        let path = self.path.display();
+        let err = OpenOptions::new().append(write).read(true).open(&self.path);
+
+        match err {
+            Err(e) => match e.kind() {
+                io::ErrorKind::NotFound => Err(err!(
+                    FileNotFound,
+                    "Did you load thinkpad_acpi?",
+                    "File {} not found.",
+                    path
+                )
+                .with_source(e)),
+                io::ErrorKind::PermissionDenied if write => Err(err!(
+                    PermissionDenied,
+                    "Do you have root permissions?",
+                    "while trying to write to {}",
+                    path
+                )
+                .with_source(e)),
+                io::ErrorKind::PermissionDenied => Err(err!(
+                    PermissionDenied,
+                    "Do you have sufficient permissions?",
+                    "while trying to read from {}",
+                    path
+                )
+                .with_source(e)),
+                _ => Err(generic_err!(e)),
+            },
+            Ok(f) => Ok(f),
+        }
+    }
+
+    fn read_status(file: &mut File) -> Result<FanStatus, Error> {
+        let mut s = String::new();
+        if let Err(e) = file.read_to_string(&mut s) {
+            return Err(generic_err!(e));
+        }
+
+        get_fan_status_from_proc_output(s)
+    }
+
+    /// Write a command like `level 7` or `watchdog 30`, if the kernel advertises it.
+    fn write_command(&self, command: &str) -> Result<(), Error> {
+        let mut file = self.open(true)?;
+        let name = command.split_whitespace().next().unwrap_or_default();
+
+        if !Self::read_status(&mut file)?.supports(name) {
+            return Err(err!(
+                FanControlDisabled,
+                "Did you load thinkpad_acpi with fan_control=1?",
+                "Can't control the fan speed"
+            ));
+        }
+
+        match file.write_all(command.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => match e.kind() {
+                io::ErrorKind::InvalidInput => Err(err!(
+                    FanControlDisabled,
+                    "Did you load thinkpad_acpi with fan_control=1",
+                    "Can't control the fan speed. "
+                )
+                .with_source(e)),
+                _ => Err(generic_err!(e)),
+            },
+        }
+    }
+}
+
+fn check_watchdog_timeout(timeout: u8) -> Result<(), Error> {
+    if timeout > MAX_WATCHDOG_TIMEOUT {
+        return Err(err!(
+            ValueTooHigh,
+            "The watchdog timeout ranges from 0 (off) to 120 seconds",
+            "Watchdog timeout {} is too high",
+            timeout
+        ));
+    }
+
+    Ok(())
+}
+
+impl FanBackend for ProcfsBackend {
+    fn status(&mut self) -> Result<FanStatus, Error> {
+        let mut file = self.open(false)?;
+        let mut status = Self::read_status(&mut file)?;
+
+        // procfs doesn't tell, but the driver attribute does
+        status.watchdog = std::fs::read_to_string(&self.watchdog)
+            .ok()
+            .and_then(|timeout| timeout.trim().parse().ok());
+
+        Ok(status)
+    }
+
+    fn fans(&mut self) -> Result<Vec<FanReading>, Error> {
+        let fans = hwmon::find_chip_dir(&self.hwmon, "thinkpad")
+            .map(hwmon::read_fan_inputs)
+            .unwrap_or_default();
+
+        if fans.is_empty() {
+            return Ok(vec![FanReading {
+                id: 1,
+                rpm: self.rpm()?,
+            }]);
+        }
+
+        Ok(fans
+            .into_iter()
+            .map(|(id, rpm)| FanReading { id, rpm })
+            .collect())
+    }
+
+    fn set_level(&mut self, fs: FanSpeed) -> Result<(), Error> {
+        self.write_command(&format!("level {fs}"))
+    }
+
+    fn set_watchdog(&mut self, timeout: u8) -> Result<(), Error> {
+        check_watchdog_timeout(timeout)?;
+        self.write_command(&format!("watchdog {timeout}"))
+    }
+
+    fn enable(&mut self) -> Result<(), Error> {
+        self.write_command("enable")
+    }
+
+    fn disable(&mut self) -> Result<(), Error> {
+        self.write_command("disable")
+    }
+
+    fn set_pwm(&mut self, pwm: u8) -> Result<(), Error> {
+        let Some(dir) =
+            hwmon::find_chip_dir(&self.hwmon, "thinkpad").filter(|dir| dir.join("pwm1").exists())
+        else {
+            // older kernels: go through procfs with the same mapping the driver uses
+            return self.set_level(FanSpeed::from_pwm(pwm));
+        };
+
+        // 1 means manual control through pwm1, 2 would hand the fan back to the EC
+        hwmon::write_attr(&dir, "pwm1_enable", 1)?;
+        // below 32 the driver sets level 0, see FanSpeed::from_pwm
+        hwmon::write_attr(&dir, "pwm1", pwm.max(32))
+    }
+}
+
+/// An in-memory fan for testing code built on [`FanBackend`].
+///
+/// Every level written is recorded and reflected in the next status. RPM
+/// readings pushed with [`MockBackend::with_rpm_readings`] are served one per
+/// status read; once they run out the last reading sticks.
+#[derive(Debug, Clone)]
+pub struct MockBackend {
+    status: FanStatus,
+    rpm_readings: VecDeque<u16>,
+    writes: Vec<FanSpeed>,
+    extra_fans: Vec<FanReading>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockBackend {
+    /// A controllable fan at `auto`, spinning at 0 RPM.
+    pub fn new() -> Self {
+        Self::with_status(FanStatus {
+            enabled: true,
+            speed: 0,
+            level: FanSpeed::Auto,
+            watchdog: Some(0),
+            commands: vec![
+                "level <level> (<level> is 0-7, auto, disengaged, full-speed)".to_string(),
+                "enable, disable".to_string(),
+                "watchdog <timeout> (<timeout> is 0 (off), 1-120 (seconds))".to_string(),
+            ],
+        })
+    }
+
+    pub fn with_status(status: FanStatus) -> Self {
+        Self {
+            status,
+            rpm_readings: VecDeque::new(),
+            writes: Vec::new(),
+            extra_fans: Vec::new(),
+        }
+    }
+
+    /// Add fans besides the first one, whose speed comes from the status.
+    pub fn with_extra_fans(mut self, fans: impl IntoIterator<Item = FanReading>) -> Self {
+        self.extra_fans.extend(fans);
+        self
+    }
+
+    pub fn with_rpm_readings(mut self, readings: impl IntoIterator<Item = u16>) -> Self {
+        self.rpm_readings.extend(readings);
+        self
+    }
+
+    pub fn push_rpm_reading(&mut self, rpm: u16) {
+        self.rpm_readings.push_back(rpm);
+    }
+
+    /// Every level passed to [`FanBackend::set_level`], oldest first.
+    pub fn writes(&self) -> &[FanSpeed] {
+        &self.writes
+    }
+
+    pub fn last_write(&self) -> Option<FanSpeed> {
+        self.writes.last().copied()
+    }
+
+    fn check_supported(&self, command: &str) -> Result<(), Error> {
+        if !self.status.supports(command) {
+            return Err(err!(
+                FanControlDisabled,
+                "Did you load thinkpad_acpi with fan_control=1?",
+                "Can't control the fan speed"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl FanBackend for MockBackend {
+    fn status(&mut self) -> Result<FanStatus, Error> {
+        if let Some(rpm) = self.rpm_readings.pop_front() {
+            self.status.speed = rpm;
+        }
+
+        Ok(self.status.clone())
+    }
+
+    fn fans(&mut self) -> Result<Vec<FanReading>, Error> {
+        let mut fans = vec![FanReading {
+            id: 1,
+            rpm: self.rpm()?,
+        }];
+
+        fans.extend(self.extra_fans.iter().copied());
+        Ok(fans)
+    }
+
+    fn set_level(&mut self, fs: FanSpeed) -> Result<(), Error> {
+        self.check_supported("level")?;
+
+        self.writes.push(fs);
+        self.status.level = fs;
+        Ok(())
+    }
+
+    fn set_watchdog(&mut self, timeout: u8) -> Result<(), Error> {
+        check_watchdog_timeout(timeout)?;
+        self.check_supported("watchdog")?;
+
+        self.status.watchdog = Some(timeout);
+        Ok(())
+    }
+
+    fn enable(&mut self) -> Result<(), Error> {
+        self.check_supported("enable")?;
+
+        self.status.enabled = true;
+        self.status.level = FanSpeed::Auto;
+        Ok(())
+    }
+
+    fn disable(&mut self) -> Result<(), Error> {
+        self.check_supported("disable")?;
+
+        self.status.enabled = false;
+        self.status.level = FanSpeed::Level(0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_commands() -> MockBackend {
+        MockBackend::with_status(FanStatus {
+            commands: Vec::new(),
+            ..MockBackend::new().status().unwrap()
+        })
+    }
+
+    #[test]
+    fn set_level_is_recorded_and_read_back() {
+        let mut fan = MockBackend::new();
+
+        fan.set_level(FanSpeed::Level(7)).unwrap();
+        fan.set_level(FanSpeed::FullSpeed).unwrap();
+
+        assert_eq!(fan.writes(), [FanSpeed::Level(7), FanSpeed::FullSpeed]);
+        assert_eq!(fan.status().unwrap().level, FanSpeed::FullSpeed);
+    }
+
+    #[test]
+    fn rpm_readings_run_out_on_the_last_one() {
+        let mut fan = MockBackend::new().with_rpm_readings([2900, 3100]);
+
+        assert_eq!(fan.rpm().unwrap(), 2900);
+        assert_eq!(fan.rpm().unwrap(), 3100);
+        assert_eq!(fan.rpm().unwrap(), 3100);
+
+        fan.push_rpm_reading(0);
+        assert_eq!(fan.rpm().unwrap(), 0);
+    }
+
+    #[test]
+    fn set_pwm_maps_to_a_level() {
+        let mut fan = MockBackend::new();
+
+        for pwm in [0, 31, 32, 64, 128, 255] {
+            fan.set_pwm(pwm).unwrap();
+        }
+
+        assert_eq!(fan.writes(), [1, 1, 1, 2, 4, 7].map(FanSpeed::Level));
+    }
+
+    /// A thinkpad hwmon device with `pwm1` next to a procfs fan file, both under `dir`.
+    fn procfs_with_pwm(test: &str) -> (std::path::PathBuf, ProcfsBackend) {
+        let dir = crate::test_dir(&format!("backend-{test}"));
+        let chip = dir.join("hwmon/hwmon5");
+        std::fs::create_dir_all(&chip).unwrap();
+        std::fs::write(chip.join("name"), "thinkpad\n").unwrap();
+        std::fs::write(chip.join("pwm1"), "255\n").unwrap();
+        std::fs::write(chip.join("pwm1_enable"), "2\n").unwrap();
+        std::fs::write(
+            dir.join("fan"),
+            "status:\t\tenabled\nspeed:\t\t2900\nlevel:\t\tauto\ncommands:\tlevel <level>\n",
+        )
+        .unwrap();
+
+        let backend = ProcfsBackend::new(dir.join("fan")).with_hwmon(dir.join("hwmon"));
+        (chip, backend)
+    }
+
+    #[test]
+    fn procfs_set_pwm_goes_through_hwmon() {
+        let (chip, mut fan) = procfs_with_pwm("set_pwm");
+        let read = |attr: &str| std::fs::read_to_string(chip.join(attr)).unwrap();
+
+        fan.set_pwm(200).unwrap();
+        assert_eq!(read("pwm1_enable"), "1");
+        assert_eq!(read("pwm1"), "200");
+
+        fan.set_pwm(10).unwrap();
+        assert_eq!(read("pwm1"), "32");
+
+        // pwm1_enable comes first: if it can't be written, pwm1 is left alone
+        std::fs::remove_file(chip.join("pwm1_enable")).unwrap();
+        std::fs::create_dir(chip.join("pwm1_enable")).unwrap();
+        assert!(fan.set_pwm(255).is_err());
+        assert_eq!(read("pwm1"), "32");
+
+        // without pwm1, through procfs
+        std::fs::remove_file(chip.join("pwm1")).unwrap();
+        fan.set_pwm(0).unwrap();
+        assert!(std::fs::read_to_string(fan.path())
+            .unwrap()
+            .ends_with("level 1"));
+    }
+
+    #[test]
+    fn fans_include_the_extra_ones() {
+        let mut fan = MockBackend::new()
+            .with_rpm_readings([2900])
+            .with_extra_fans([FanReading { id: 2, rpm: 3000 }]);
+
+        assert_eq!(
+            fan.fans().unwrap(),
+            [
+                FanReading { id: 1, rpm: 2900 },
+                FanReading { id: 2, rpm: 3000 }
+            ]
+        );
+    }
+
+    #[test]
+    fn set_fan_level() {
+        let mut fan = MockBackend::new();
+        fan.set_fan_level(1, FanSpeed::Level(4)).unwrap();
+        assert_eq!(fan.last_write(), Some(FanSpeed::Level(4)));
+
+        let e = fan.set_fan_level(2, FanSpeed::Level(4)).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::InvalidValue));
+
+        // every fan runs at the same level, so one can't be set on its own
+        let mut fans = MockBackend::new().with_extra_fans([FanReading { id: 2, rpm: 0 }]);
+        let e = fans.set_fan_level(2, FanSpeed::Level(4)).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Unsupported));
+        assert!(fans.writes().is_empty());
+    }
+
+    #[test]
+    fn watchdog() {
+        let mut fan = MockBackend::new();
+
+        fan.set_watchdog(30).unwrap();
+        assert_eq!(fan.status().unwrap().watchdog, Some(30));
+
+        let e = fan.set_watchdog(MAX_WATCHDOG_TIMEOUT + 1).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ValueTooHigh));
+        assert_eq!(fan.status().unwrap().watchdog, Some(30));
+    }
+
+    #[test]
+    fn enable_and_disable() {
+        let mut fan = MockBackend::new();
+        fan.set_level(FanSpeed::Level(5)).unwrap();
+
+        fan.disable().unwrap();
+        let status = fan.status().unwrap();
+        assert!(!status.enabled);
+        assert_eq!(status.level, FanSpeed::Level(0));
+
+        fan.enable().unwrap();
+        let status = fan.status().unwrap();
+        assert!(status.enabled);
+        assert_eq!(status.level, FanSpeed::Auto);
+    }
+
+    #[test]
+    fn refuses_without_fan_control() {
+        let mut fan = without_commands();
+
+        for res in [
+            fan.set_level(FanSpeed::Level(7)),
+            fan.set_pwm(255),
+            fan.set_watchdog(30),
+            fan.enable(),
+            fan.disable(),
+        ] {
+            assert!(matches!(
+                res.unwrap_err().kind(),
+                ErrorKind::FanControlDisabled
+            ));
+        }
+        assert!(fan.writes().is_empty());
+        assert!(!fan.status().unwrap().is_controllable());
+    }
+}
@@ -0,0 +1,229 @@
+//! The config file of the fan control daemon, `tpfand`.
+
+use crate::{
+    curve::FanCurve, error::*, failsafe::FailsafePolicy, ipc, profile::Profile, FanSpeed,
+    SensorReading, TempSource,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/tpfand.toml";
+
+/// ```toml
+/// interval = 2
+/// socket = "/run/tpfand.sock"
+/// dbus = "system"
+/// metrics = "127.0.0.1:9142"
+/// failsafe = "full-speed"
+/// sensors = [
+///     { chip = "coretemp", label = "Package id 0" },
+///     { chip = "thinkpad" },
+/// ]
+///
+/// [overheat]
+/// limit = "max"
+/// margin = 5
+///
+/// [curve]
+/// base = "auto"
+/// points = [
+///     { speed = 3, rising = 60, falling = 55 },
+///     { speed = 7, rising = 75, falling = 70 },
+/// ]
+///
+/// [profiles]
+/// silent = { level = 1 }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Seconds between two readings.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// The level to use while no temperature can be read.
+    #[serde(default = "default_failsafe")]
+    pub failsafe: FanSpeed,
+    /// Where to listen for [`ipc::Request`]s.
+    #[serde(default = "default_socket")]
+    pub socket: PathBuf,
+    /// Where the profile set last is kept, see [`crate::profile`].
+    #[serde(default = "default_profile_state")]
+    pub profile_state: PathBuf,
+    /// The D-Bus to serve the fan on: `system`, `session` or a bus address
+    /// like `unix:path=/run/dbus/system_bus_socket`. Off if left out.
+    pub dbus: Option<String>,
+    /// Serve Prometheus metrics on `http://<address>/metrics`. Off if left out.
+    pub metrics: Option<SocketAddr>,
+    #[serde(default)]
+    pub temp_source: TempSource,
+    /// The sensors to watch; the hottest one drives the curve. If empty, the
+    /// average CPU core temperature is used.
+    #[serde(default)]
+    pub sensors: Vec<SensorSelector>,
+    /// Overrides the curve and manual levels when a core gets too hot.
+    #[serde(default)]
+    pub overheat: FailsafePolicy,
+    pub curve: FanCurve,
+    /// Named levels and curves, on top of (or replacing) the built-in ones,
+    /// see [`Config::all_profiles`].
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Picks sensors out of [`crate::get_sensor_readings`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorSelector {
+    /// The start of the chip name: `coretemp` matches every `coretemp-isa-*`.
+    pub chip: String,
+    /// The sensor label, e.g. `Package id 0`. Every sensor of the chip if left out.
+    pub label: Option<String>,
+}
+
+fn default_interval() -> u64 {
+    2
+}
+
+fn default_socket() -> PathBuf {
+    PathBuf::from(ipc::DEFAULT_SOCKET_PATH)
+}
+
+fn default_profile_state() -> PathBuf {
+    PathBuf::from(crate::profile::STATE_PATH)
+}
+
+fn default_failsafe() -> FanSpeed {
+    FanSpeed::FullSpeed
+}
+
+impl SensorSelector {
+    pub fn matches(&self, reading: &SensorReading) -> bool {
+        reading.chip.starts_with(&self.chip)
+            && self
+                .label
+                .as_ref()
+                .is_none_or(|label| *label == reading.label)
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+
+        match fs::read_to_string(path) {
+            Ok(s) => Self::from_toml(&s),
+            Err(e) => match e.kind() {
+                io::ErrorKind::NotFound => Err(err!(
+                    FileNotFound,
+                    "Create it, or pass another one with --config",
+                    "Config file {} not found",
+                    path.display()
+                )
+                .with_source(e)),
+                io::ErrorKind::PermissionDenied => Err(err!(
+                    PermissionDenied,
+                    "Do you have sufficient permissions?",
+                    "while trying to read from {}",
+                    path.display()
+                )
+                .with_source(e)),
+                _ => Err(generic_err!(e)),
+            },
+        }
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, Error> {
+        let config: Self = toml::from_str(s).map_err(|e| {
+            err!(
+                MalformedConfig,
+                "See tpfand/tpfand.toml for an example",
+                "{}",
+                e.to_string().trim_end()
+            )
+            .with_source(e)
+        })?;
+
+        if config.interval == 0 {
+            return Err(err!(
+                MalformedConfig,
+                "The interval is in seconds, and at least 1",
+                "interval can't be 0"
+            ));
+        }
+
+        // `setfan` takes both, so they can't be told apart
+        if let Some(name) = config
+            .profiles
+            .keys()
+            .find(|name| FanSpeed::from_string(name.to_string()).is_ok())
+        {
+            return Err(err!(
+                MalformedConfig,
+                "Give the profile a name, not a fan speed",
+                "The profile {} is named like a fan speed",
+                name
+            ));
+        }
+
+        Ok(config)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    /// The built-in profiles, with `balanced` following [`Config::curve`],
+    /// then the ones from `[profiles]`.
+    pub fn all_profiles(&self) -> BTreeMap<String, Profile> {
+        let mut profiles = crate::profile::builtin();
+        profiles.insert("balanced".to_string(), Profile::Curve(self.curve.clone()));
+        profiles.extend(self.profiles.clone());
+
+        profiles
+    }
+
+    /// Look up a profile from [`Config::all_profiles`].
+    pub fn profile(&self, name: &str) -> Result<Profile, Error> {
+        crate::profile::lookup(self.all_profiles(), name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVE: &str = "[curve]\nbase = \"auto\"\npoints = []\n";
+
+    #[test]
+    fn defaults() {
+        let config = Config::from_toml(CURVE).unwrap();
+
+        assert_eq!(config.interval(), Duration::from_secs(2));
+        assert_eq!(config.socket, Path::new(ipc::DEFAULT_SOCKET_PATH));
+        assert_eq!(config.profile_state, Path::new(crate::profile::STATE_PATH));
+    }
+
+    #[test]
+    fn profiles_named_like_fan_speeds_are_rejected() {
+        for name in ["3", "auto", "full-speed", "disengaged"] {
+            let toml = format!("{CURVE}[profiles]\n\"{name}\" = {{ level = 2 }}\n");
+            let e = Config::from_toml(&toml).unwrap_err();
+            assert_eq!(e.kind(), &ErrorKind::MalformedConfig, "{name}");
+        }
+
+        // level 0 can't be set, so it doesn't clash
+        let toml =
+            format!("{CURVE}[profiles]\nsilent = {{ level = 1 }}\n\"0\" = {{ level = 1 }}\n");
+        let config = Config::from_toml(&toml).unwrap();
+        assert_eq!(
+            config.profile("silent").unwrap(),
+            Profile::Level(FanSpeed::Level(1))
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! Map temperatures to fan speeds, with hysteresis.
+//!
+//! Everything here is pure: [`FanCurve::evaluate`] only looks at its arguments,
+//! so curves can be tested without any hardware.
+
+use crate::{error::*, FanSpeed, MilliCelsius};
+use serde::{Deserialize, Serialize};
+
+/// One step of a curve. The fan goes to `speed` once the temperature reaches
+/// `rising`, and only leaves it again once the temperature drops below `falling`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CurvePoint {
+    pub speed: FanSpeed,
+    pub rising: MilliCelsius,
+    pub falling: MilliCelsius,
+}
+
+/// An ordered list of [`CurvePoint`]s, on top of a base speed used below the first one.
+///
+/// In config files, temperatures are in degrees:
+///
+/// ```toml
+/// base = "auto"
+/// points = [
+///     { speed = 3, rising = 60, falling = 55 },
+///     { speed = 7, rising = 75, falling = 70 },
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawFanCurve", into = "RawFanCurve")]
+pub struct FanCurve {
+    base: FanSpeed,
+    points: Vec<CurvePoint>,
+}
+
+impl FanCurve {
+    /// The points must be ordered by rising threshold, and every point must
+    /// fall at or below where it rises. Every speed may only be used once,
+    /// including the base, so the last speed tells where on the curve it was.
+    pub fn new(base: FanSpeed, points: Vec<CurvePoint>) -> Result<Self, Error> {
+        const HELP: &str = "Order the points by temperature, with falling <= rising";
+
+        for (i, point) in points.iter().enumerate() {
+            if point.speed.is_same_as(base) {
+                return Err(err!(
+                    InvalidValue,
+                    "Every point needs a speed of its own",
+                    "Point {} has the same speed as the base ({})",
+                    i,
+                    base
+                ));
+            }
+
+            if let Some(j) = points[..i]
+                .iter()
+                .position(|p| p.speed.is_same_as(point.speed))
+            {
+                return Err(err!(
+                    InvalidValue,
+                    "Every point needs a speed of its own",
+                    "Points {} and {} have the same speed ({})",
+                    j,
+                    i,
+                    point.speed
+                ));
+            }
+
+            if point.falling > point.rising {
+                return Err(err!(
+                    InvalidValue,
+                    HELP,
+                    "Point {} falls at {}°C, above where it rises ({}°C)",
+                    i,
+                    point.falling,
+                    point.rising
+                ));
+            }
+
+            if let Some(prev) = i.checked_sub(1).map(|prev| &points[prev]) {
+                if point.rising <= prev.rising || point.falling < prev.falling {
+                    return Err(err!(
+                        InvalidValue,
+                        HELP,
+                        "Point {} is not above point {}",
+                        i,
+                        i - 1
+                    ));
+                }
+            }
+        }
+
+        Ok(Self { base, points })
+    }
+
+    pub fn base(&self) -> FanSpeed {
+        self.base
+    }
+
+    pub fn points(&self) -> &[CurvePoint] {
+        &self.points
+    }
+
+    /// Get the speed for `temp`, given the speed the curve picked last time.
+    ///
+    /// The fan speeds up as soon as a rising threshold is reached, but only
+    /// slows down step by step as the falling thresholds are crossed. If
+    /// `previous` isn't on the curve (e.g. on the first run), only the rising
+    /// thresholds count.
+    pub fn evaluate(&self, temp: MilliCelsius, previous: Option<FanSpeed>) -> FanSpeed {
+        // step 0 is the base, step i is points[i - 1]
+        let up = self
+            .points
+            .iter()
+            .rposition(|point| temp >= point.rising)
+            .map_or(0, |i| i + 1);
+
+        // `previous` may have been read back from procfs, where full-speed is disengaged
+        let current = previous.and_then(|previous| {
+            if let Some(i) = self
+                .points
+                .iter()
+                .position(|p| p.speed.is_same_as(previous))
+            {
+                Some(i + 1)
+            } else {
+                self.base.is_same_as(previous).then_some(0)
+            }
+        });
+
+        let step = match current {
+            Some(current) if current > up => {
+                let mut step = current;
+                while step > up && temp < self.points[step - 1].falling {
+                    step -= 1;
+                }
+                step
+            }
+            _ => up,
+        };
+
+        self.speed_at(step)
+    }
+
+    fn speed_at(&self, step: usize) -> FanSpeed {
+        match step {
+            0 => self.base,
+            _ => self.points[step - 1].speed,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawCurvePoint {
+    speed: FanSpeed,
+    rising: f64,
+    falling: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawFanCurve {
+    base: FanSpeed,
+    points: Vec<RawCurvePoint>,
+}
+
+impl TryFrom<RawFanCurve> for FanCurve {
+    type Error = Error;
+
+    fn try_from(raw: RawFanCurve) -> Result<Self, Self::Error> {
+        let points = raw
+            .points
+            .into_iter()
+            .map(|point| CurvePoint {
+                speed: point.speed,
+                rising: MilliCelsius::from_celsius(point.rising),
+                falling: MilliCelsius::from_celsius(point.falling),
+            })
+            .collect();
+
+        FanCurve::new(raw.base, points)
+    }
+}
+
+impl From<FanCurve> for RawFanCurve {
+    fn from(curve: FanCurve) -> Self {
+        Self {
+            base: curve.base,
+            points: curve
+                .points
+                .into_iter()
+                .map(|point| RawCurvePoint {
+                    speed: point.speed,
+                    rising: point.rising.as_celsius(),
+                    falling: point.falling.as_celsius(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c(celsius: f64) -> MilliCelsius {
+        MilliCelsius::from_celsius(celsius)
+    }
+
+    fn point(level: u8, rising: f64, falling: f64) -> CurvePoint {
+        CurvePoint {
+            speed: FanSpeed::Level(level),
+            rising: c(rising),
+            falling: c(falling),
+        }
+    }
+
+    /// auto, 3 from 60°C (until below 55°C), 7 from 75°C (until below 70°C)
+    fn curve() -> FanCurve {
+        FanCurve::new(
+            FanSpeed::Auto,
+            vec![point(3, 60.0, 55.0), point(7, 75.0, 70.0)],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn rising() {
+        let curve = curve();
+        let mut level = None;
+
+        for (temp, expected) in [
+            (40.0, FanSpeed::Auto),
+            (59.9, FanSpeed::Auto),
+            (60.0, FanSpeed::Level(3)),
+            (74.0, FanSpeed::Level(3)),
+            (75.0, FanSpeed::Level(7)),
+            (90.0, FanSpeed::Level(7)),
+        ] {
+            level = Some(curve.evaluate(c(temp), level));
+            assert_eq!(level, Some(expected), "at {temp}°C");
+        }
+    }
+
+    #[test]
+    fn falling_hysteresis() {
+        let curve = curve();
+        let mut level = Some(FanSpeed::Level(7));
+
+        for (temp, expected) in [
+            // below the rising threshold, but not below the falling one
+            (72.0, FanSpeed::Level(7)),
+            (70.0, FanSpeed::Level(7)),
+            (69.9, FanSpeed::Level(3)),
+            (56.0, FanSpeed::Level(3)),
+            (54.9, FanSpeed::Auto),
+            (58.0, FanSpeed::Auto),
+        ] {
+            level = Some(curve.evaluate(c(temp), level));
+            assert_eq!(level, Some(expected), "at {temp}°C");
+        }
+    }
+
+    #[test]
+    fn falls_several_steps_at_once() {
+        assert_eq!(
+            curve().evaluate(c(40.0), Some(FanSpeed::Level(7))),
+            FanSpeed::Auto
+        );
+    }
+
+    #[test]
+    fn first_run_only_rises() {
+        let curve = curve();
+
+        // within both hysteresis bands, where a running fan would stay up
+        assert_eq!(curve.evaluate(c(72.0), None), FanSpeed::Level(3));
+        assert_eq!(curve.evaluate(c(57.0), None), FanSpeed::Auto);
+    }
+
+    #[test]
+    fn unknown_previous_only_rises() {
+        let curve = curve();
+
+        // e.g. set by hand before the curve took over again
+        assert_eq!(
+            curve.evaluate(c(72.0), Some(FanSpeed::Level(5))),
+            FanSpeed::Level(3)
+        );
+        assert_eq!(
+            curve.evaluate(c(57.0), Some(FanSpeed::FullSpeed)),
+            FanSpeed::Auto
+        );
+    }
+
+    #[test]
+    fn full_speed_read_back_as_disengaged() {
+        let curve = FanCurve::new(
+            FanSpeed::Auto,
+            vec![
+                point(3, 60.0, 55.0),
+                CurvePoint {
+                    speed: FanSpeed::FullSpeed,
+                    rising: c(80.0),
+                    falling: c(70.0),
+                },
+            ],
+        )
+        .unwrap();
+
+        // still within the hysteresis band of full-speed
+        assert_eq!(
+            curve.evaluate(c(72.0), Some(FanSpeed::Disengaged)),
+            FanSpeed::FullSpeed
+        );
+        assert_eq!(
+            curve.evaluate(c(69.0), Some(FanSpeed::Disengaged)),
+            FanSpeed::Level(3)
+        );
+
+        // and as the base
+        let curve = FanCurve::new(FanSpeed::FullSpeed, vec![]).unwrap();
+        assert_eq!(
+            curve.evaluate(c(40.0), Some(FanSpeed::Disengaged)),
+            FanSpeed::FullSpeed
+        );
+    }
+
+    #[test]
+    fn rejects_unordered_points() {
+        assert!(FanCurve::new(FanSpeed::Auto, vec![point(3, 60.0, 65.0)]).is_err());
+        assert!(FanCurve::new(
+            FanSpeed::Auto,
+            vec![point(7, 75.0, 70.0), point(3, 60.0, 55.0)]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_duplicate_speeds() {
+        assert!(FanCurve::new(
+            FanSpeed::Auto,
+            vec![point(3, 60.0, 55.0), point(3, 75.0, 70.0)]
+        )
+        .is_err());
+        assert!(FanCurve::new(FanSpeed::Level(3), vec![point(3, 60.0, 55.0)]).is_err());
+
+        let full_speed = CurvePoint {
+            speed: FanSpeed::FullSpeed,
+            ..point(0, 80.0, 75.0)
+        };
+        let disengaged = CurvePoint {
+            speed: FanSpeed::Disengaged,
+            ..point(0, 90.0, 85.0)
+        };
+        assert!(FanCurve::new(FanSpeed::Auto, vec![full_speed, disengaged]).is_err());
+    }
+}
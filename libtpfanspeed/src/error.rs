@@ -1,11 +1,17 @@
-#[derive(Debug, Clone)]
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Error {
     kind: ErrorKind,
     description: Option<String>,
     help: Option<String>,
+    /// The error this one was made from, e.g. an `io::Error`. Not sent over IPC.
+    #[serde(skip)]
+    source: Option<Arc<dyn std::error::Error + Send + Sync>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ErrorKind {
     PermissionDenied,
     FanControlDisabled,
@@ -13,6 +19,13 @@ pub enum ErrorKind {
     FileNotFound,
     ValueTooHigh,
     ValueTooLow,
+    MalformedProcfs,
+    MalformedSensorJson,
+    MissingSensorChip,
+    NoReadings,
+    MalformedConfig,
+    Ipc,
+    Unsupported,
     GenericError,
 }
 
@@ -49,11 +62,12 @@ macro_rules! err {
 }
 
 #[macro_export]
-/// Quickly construct a Generic Error.
+/// Quickly construct a Generic Error, keeping `err` as its source.
 macro_rules! generic_err {
-    ($err:expr) => {
-        $crate::err!(GenericError, "Some error occurred: {}", $err)
-    };
+    ($err:expr) => {{
+        let err = $err;
+        $crate::err!(GenericError, "Some error occurred: {}", err).with_source(err)
+    }};
 }
 
 pub use crate::{err, generic_err};
@@ -62,12 +76,19 @@ impl std::fmt::Display for ErrorKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use ErrorKind as E;
         let s = match self {
-            E::ValueTooLow => "Fan speed setting too low",
-            E::ValueTooHigh => "Fan speed setting too high",
-            E::InvalidValue => "Fan speed setting invalid",
+            E::ValueTooLow => "Value too low",
+            E::ValueTooHigh => "Value too high",
+            E::InvalidValue => "Invalid value",
             E::FileNotFound => "File not found",
             E::PermissionDenied => "Permission Denied",
             E::FanControlDisabled => "Fan control disabled",
+            E::MalformedProcfs => "Malformed procfs content",
+            E::MalformedSensorJson => "Malformed sensor JSON",
+            E::MissingSensorChip => "Missing sensor chip",
+            E::NoReadings => "No temperature readings",
+            E::MalformedConfig => "Malformed config file",
+            E::Ipc => "tpfand communication error",
+            E::Unsupported => "Not supported",
             E::GenericError => "Generic error",
         };
 
@@ -75,12 +96,39 @@ impl std::fmt::Display for ErrorKind {
     }
 }
 
+impl ErrorKind {
+    /// The exit status of tpfanctl and tpfand for this kind of error, see the
+    /// [README](https://github.com/ezntek/tpfanspeed#exit-codes) for the table.
+    /// 2 is left to clap for usage errors.
+    pub fn exit_code(&self) -> i32 {
+        use ErrorKind as E;
+
+        match self {
+            E::GenericError => 1,
+            E::FileNotFound => 3,
+            E::PermissionDenied => 4,
+            E::FanControlDisabled => 5,
+            E::InvalidValue => 6,
+            E::ValueTooHigh => 7,
+            E::ValueTooLow => 8,
+            E::MalformedProcfs => 9,
+            E::MalformedSensorJson => 10,
+            E::MissingSensorChip => 11,
+            E::NoReadings => 12,
+            E::Unsupported => 13,
+            E::MalformedConfig => 14,
+            E::Ipc => 15,
+        }
+    }
+}
+
 impl Error {
     pub fn new(kind: ErrorKind, description: String, help: String) -> Self {
         Self {
             kind,
             description: Some(description),
             help: Some(help),
+            source: None,
         }
     }
 
@@ -89,6 +137,7 @@ impl Error {
             kind,
             description: None,
             help: None,
+            source: None,
         }
     }
 
@@ -97,6 +146,7 @@ impl Error {
             kind,
             description: None,
             help: Some(help),
+            source: None,
         }
     }
 
@@ -105,9 +155,16 @@ impl Error {
             kind,
             description: Some(description),
             help: None,
+            source: None,
         }
     }
 
+    /// Keep `source` as the cause of this error, see [`std::error::Error::source`].
+    pub fn with_source(mut self, source: impl std::error::Error + Send + Sync + 'static) -> Self {
+        self.source = Some(Arc::new(source));
+        self
+    }
+
     pub fn kind(&self) -> &ErrorKind {
         &self.kind
     }
@@ -148,10 +205,70 @@ impl std::error::Error for Error {
     }
 
     fn cause(&self) -> Option<&dyn std::error::Error> {
-        None
+        self.source()
     }
 
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
+        self.source
+            .as_deref()
+            .map(|source| source as &(dyn std::error::Error + 'static))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{error::Error as _, io};
+
+    #[test]
+    fn sources_are_kept() {
+        let e = err!(FileNotFound, "Is it there?", "Can't open {}", "/nowhere")
+            .with_source(io::Error::from(io::ErrorKind::NotFound));
+        let source = e.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.kind(), io::ErrorKind::NotFound);
+
+        let e = generic_err!(io::Error::other("oops"));
+        assert_eq!(e.kind(), &ErrorKind::GenericError);
+        assert_eq!(e.desc(), Some("oops"));
+        assert_eq!(e.source().unwrap().to_string(), "oops");
+
+        // clones share it, but it isn't sent over IPC
+        assert!(e.clone().source().is_some());
+        let json = serde_json::to_string(&e).unwrap();
+        let e: Error = serde_json::from_str(&json).unwrap();
+        assert_eq!(e.desc(), Some("oops"));
+        assert!(e.source().is_none());
+
+        assert!(err!(InvalidValue, "no cause").source().is_none());
+    }
+
+    #[test]
+    fn exit_codes() {
+        use ErrorKind as E;
+
+        let codes = [
+            (E::GenericError, 1),
+            (E::FileNotFound, 3),
+            (E::PermissionDenied, 4),
+            (E::FanControlDisabled, 5),
+            (E::InvalidValue, 6),
+            (E::ValueTooHigh, 7),
+            (E::ValueTooLow, 8),
+            (E::MalformedProcfs, 9),
+            (E::MalformedSensorJson, 10),
+            (E::MissingSensorChip, 11),
+            (E::NoReadings, 12),
+            (E::Unsupported, 13),
+            (E::MalformedConfig, 14),
+            (E::Ipc, 15),
+        ];
+
+        // and as documented in the README
+        let readme = include_str!("../../README.md");
+        for (kind, code) in codes {
+            assert_eq!(kind.exit_code(), code, "{kind:?}");
+            let row = format!("| {code:<6} | `{kind:?}`");
+            assert!(readme.contains(&row), "{row} is missing from the README");
+        }
     }
 }
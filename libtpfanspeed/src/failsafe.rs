@@ -0,0 +1,404 @@
+//! Force the fan up when a core gets close to its limits, whatever level was set by hand.
+//!
+//! Like [`crate::curve`], everything here is pure: [`Failsafe::update`] only
+//! looks at the readings it is given, and it is up to the caller to set
+//! [`FailsafePolicy::level`] while [`Failsafe::trip`] is `Some`.
+
+use crate::{error::*, FanSpeed, MilliCelsius, Temperatures};
+use serde::{Deserialize, Serialize};
+
+/// Which of the limits of a [`crate::CoreTemperature`] the margin is below.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Limit {
+    #[default]
+    Max,
+    Critical,
+}
+
+/// When to force the fan up, and how far.
+///
+/// In config files, temperatures are in degrees:
+///
+/// ```toml
+/// [overheat]
+/// limit = "max"
+/// margin = 5
+/// hysteresis = 5
+/// level = "full-speed"
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawFailsafePolicy", into = "RawFailsafePolicy")]
+pub struct FailsafePolicy {
+    enabled: bool,
+    limit: Limit,
+    margin: MilliCelsius,
+    hysteresis: MilliCelsius,
+    level: FanSpeed,
+}
+
+/// Why the failsafe tripped: the reading furthest over (or, while cooling
+/// down, closest to) its threshold.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trip {
+    pub package: u8,
+    /// The label of the core or die, e.g. `Core 3` or `Tccd1`.
+    pub sensor: String,
+    pub temp: MilliCelsius,
+    /// Where the failsafe trips, `margin` below `limit_temp`.
+    pub threshold: MilliCelsius,
+    pub limit: Limit,
+    pub limit_temp: MilliCelsius,
+}
+
+/// A [`FailsafePolicy`] applied to successive readings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Failsafe {
+    policy: FailsafePolicy,
+    trip: Option<Trip>,
+}
+
+impl Default for FailsafePolicy {
+    /// 5°C below `max`, at full speed.
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            limit: Limit::Max,
+            margin: MilliCelsius(5_000),
+            hysteresis: MilliCelsius(5_000),
+            level: FanSpeed::FullSpeed,
+        }
+    }
+}
+
+impl FailsafePolicy {
+    /// The margin and hysteresis can't be negative, and the level has to cool
+    /// at least as well as `auto`: `auto`, 7, `full-speed` or `disengaged`.
+    pub fn new(
+        limit: Limit,
+        margin: MilliCelsius,
+        hysteresis: MilliCelsius,
+        level: FanSpeed,
+    ) -> Result<Self, Error> {
+        if margin.0 < 0 || hysteresis.0 < 0 {
+            return Err(err!(
+                ValueTooLow,
+                "The margin and hysteresis are in °C, and at least 0",
+                "The failsafe margin ({}°C) and hysteresis ({}°C) can't be negative",
+                margin,
+                hysteresis
+            ));
+        }
+
+        if let FanSpeed::Level(0..=6) = level {
+            return Err(err!(
+                InvalidValue,
+                "Use auto, 7, full-speed or disengaged",
+                "The failsafe level {} is too slow",
+                level
+            ));
+        }
+
+        Ok(Self {
+            enabled: true,
+            limit,
+            margin,
+            hysteresis,
+            level,
+        })
+    }
+
+    /// A policy that never trips.
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Default::default()
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn limit(&self) -> Limit {
+        self.limit
+    }
+
+    pub fn margin(&self) -> MilliCelsius {
+        self.margin
+    }
+
+    pub fn hysteresis(&self) -> MilliCelsius {
+        self.hysteresis
+    }
+
+    /// The level to force while tripped.
+    pub fn level(&self) -> FanSpeed {
+        self.level
+    }
+
+    /// The reading furthest over its threshold, lowered by `slack`, if any is.
+    fn check(&self, temps: &Temperatures, slack: MilliCelsius) -> Option<Trip> {
+        temps
+            .packages
+            .iter()
+            .flat_map(|(package, readings)| {
+                readings
+                    .cores
+                    .values()
+                    .chain(readings.dies.values())
+                    .map(move |reading| (*package, reading))
+            })
+            .filter_map(|(package, reading)| {
+                let limit_temp = match self.limit {
+                    Limit::Max => reading.max,
+                    Limit::Critical => reading.critical,
+                };
+                let threshold = MilliCelsius(limit_temp.0 - self.margin.0);
+
+                (reading.temp.0 >= threshold.0 - slack.0).then(|| Trip {
+                    package,
+                    sensor: reading.label.clone(),
+                    temp: reading.temp,
+                    threshold,
+                    limit: self.limit,
+                    limit_temp,
+                })
+            })
+            .max_by_key(|trip| trip.temp.0 - trip.threshold.0)
+    }
+}
+
+impl Failsafe {
+    pub fn new(policy: FailsafePolicy) -> Self {
+        Self { policy, trip: None }
+    }
+
+    pub fn policy(&self) -> &FailsafePolicy {
+        &self.policy
+    }
+
+    /// Why the failsafe is tripped, if it is.
+    pub fn trip(&self) -> Option<&Trip> {
+        self.trip.as_ref()
+    }
+
+    /// Take new readings into account. Trips as soon as any core or die
+    /// reaches its threshold, and only releases once all of them are
+    /// `hysteresis` below it.
+    pub fn update(&mut self, temps: &Temperatures) -> Option<&Trip> {
+        if !self.policy.enabled {
+            self.trip = None;
+            return None;
+        }
+
+        self.trip = match self.policy.check(temps, MilliCelsius(0)) {
+            Some(trip) => Some(trip),
+            // stays tripped until everything cooled down by the hysteresis
+            None if self.trip.is_some() => self.policy.check(temps, self.policy.hysteresis),
+            None => None,
+        };
+
+        self.trip.as_ref()
+    }
+
+    /// The level to set instead of `level`: the policy's level while tripped.
+    pub fn apply(&self, level: FanSpeed) -> FanSpeed {
+        match self.trip {
+            Some(_) => self.policy.level,
+            None => level,
+        }
+    }
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Limit::Max => "max",
+            Limit::Critical => "critical",
+        };
+
+        write!(f, "{s}")
+    }
+}
+
+impl std::fmt::Display for Trip {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (package {}) at {}°C, trips at {}°C ({} is {}°C)",
+            self.sensor, self.package, self.temp, self.threshold, self.limit, self.limit_temp
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFailsafePolicy {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    limit: Limit,
+    #[serde(default = "default_margin")]
+    margin: f64,
+    #[serde(default = "default_margin")]
+    hysteresis: f64,
+    #[serde(default = "default_level")]
+    level: FanSpeed,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_margin() -> f64 {
+    5.0
+}
+
+fn default_level() -> FanSpeed {
+    FanSpeed::FullSpeed
+}
+
+impl TryFrom<RawFailsafePolicy> for FailsafePolicy {
+    type Error = Error;
+
+    fn try_from(raw: RawFailsafePolicy) -> Result<Self, Self::Error> {
+        if !raw.enabled {
+            return Ok(Self::disabled());
+        }
+
+        FailsafePolicy::new(
+            raw.limit,
+            MilliCelsius::from_celsius(raw.margin),
+            MilliCelsius::from_celsius(raw.hysteresis),
+            raw.level,
+        )
+    }
+}
+
+impl From<FailsafePolicy> for RawFailsafePolicy {
+    fn from(policy: FailsafePolicy) -> Self {
+        Self {
+            enabled: policy.enabled,
+            limit: policy.limit,
+            margin: policy.margin.as_celsius(),
+            hysteresis: policy.hysteresis.as_celsius(),
+            level: policy.level,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One core at `temp`, with a max of 100°C and a critical of 110°C.
+    fn temps(temp: f64) -> Temperatures {
+        crate::get_temps_from_sensors_output(format!(
+            r#"{{
+                "coretemp-isa-0000": {{
+                    "Core 0": {{ "temp2_input": {temp}, "temp2_max": 100.0, "temp2_crit": 110.0 }},
+                    "Core 1": {{ "temp3_input": 40.0, "temp3_max": 100.0, "temp3_crit": 110.0 }}
+                }}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    fn c(celsius: f64) -> MilliCelsius {
+        MilliCelsius::from_celsius(celsius)
+    }
+
+    #[test]
+    fn trips_on_max() {
+        let mut failsafe = Failsafe::default();
+
+        assert!(failsafe.update(&temps(94.0)).is_none());
+        assert_eq!(failsafe.apply(FanSpeed::Level(2)), FanSpeed::Level(2));
+
+        let trip = failsafe.update(&temps(95.0)).unwrap().clone();
+        assert_eq!(trip.sensor, "Core 0");
+        assert_eq!(trip.threshold, c(95.0));
+        assert_eq!(trip.limit, Limit::Max);
+        assert_eq!(trip.limit_temp, c(100.0));
+        assert_eq!(failsafe.apply(FanSpeed::Level(2)), FanSpeed::FullSpeed);
+    }
+
+    #[test]
+    fn trips_on_critical() {
+        let policy =
+            FailsafePolicy::new(Limit::Critical, c(5.0), c(5.0), FanSpeed::Level(7)).unwrap();
+        let mut failsafe = Failsafe::new(policy);
+
+        // over max, but not near critical
+        assert!(failsafe.update(&temps(100.0)).is_none());
+
+        let trip = failsafe.update(&temps(105.0)).unwrap();
+        assert_eq!(trip.limit, Limit::Critical);
+        assert_eq!(trip.limit_temp, c(110.0));
+        assert_eq!(failsafe.apply(FanSpeed::Auto), FanSpeed::Level(7));
+    }
+
+    #[test]
+    fn releases_after_the_hysteresis() {
+        let mut failsafe = Failsafe::default();
+        failsafe.update(&temps(96.0));
+
+        // below the threshold, but not by the hysteresis yet
+        assert!(failsafe.update(&temps(94.0)).is_some());
+        assert!(failsafe.update(&temps(90.0)).is_some());
+        assert!(failsafe.update(&temps(89.9)).is_none());
+        assert_eq!(failsafe.apply(FanSpeed::Auto), FanSpeed::Auto);
+
+        // and trips at the threshold again, not at the lower release point
+        assert!(failsafe.update(&temps(94.0)).is_none());
+    }
+
+    #[test]
+    fn disabled_never_trips() {
+        let mut failsafe = Failsafe::new(FailsafePolicy::disabled());
+
+        assert!(failsafe.update(&temps(109.0)).is_none());
+        assert_eq!(failsafe.apply(FanSpeed::Level(1)), FanSpeed::Level(1));
+    }
+
+    #[test]
+    fn new_rejects_slow_levels_and_negative_margins() {
+        for level in 0..=6 {
+            let e = FailsafePolicy::new(Limit::Max, c(5.0), c(5.0), FanSpeed::Level(level));
+            assert_eq!(e.unwrap_err().kind(), &ErrorKind::InvalidValue, "{level}");
+        }
+        for level in [
+            FanSpeed::Level(7),
+            FanSpeed::Auto,
+            FanSpeed::FullSpeed,
+            FanSpeed::Disengaged,
+        ] {
+            assert!(FailsafePolicy::new(Limit::Max, c(5.0), c(5.0), level).is_ok());
+        }
+
+        let e = FailsafePolicy::new(Limit::Max, c(-1.0), c(5.0), FanSpeed::FullSpeed);
+        assert_eq!(e.unwrap_err().kind(), &ErrorKind::ValueTooLow);
+        let e = FailsafePolicy::new(Limit::Max, c(5.0), c(-1.0), FanSpeed::FullSpeed);
+        assert_eq!(e.unwrap_err().kind(), &ErrorKind::ValueTooLow);
+    }
+
+    #[test]
+    fn from_toml() {
+        let parse = |s: &str| toml::from_str::<FailsafePolicy>(s);
+
+        let policy = parse("limit = \"critical\"\nmargin = 2.5\nlevel = 7").unwrap();
+        assert!(policy.enabled());
+        assert_eq!(policy.limit(), Limit::Critical);
+        assert_eq!(policy.margin(), c(2.5));
+        assert_eq!(policy.hysteresis(), c(5.0));
+        assert_eq!(policy.level(), FanSpeed::Level(7));
+
+        assert_eq!(parse("").unwrap(), FailsafePolicy::default());
+        assert!(!parse("enabled = false\nlevel = 7").unwrap().enabled());
+
+        for bad in ["margin = -5", "level = 3", "limit = \"hot\"", "speed = 7"] {
+            assert!(parse(bad).is_err(), "{bad}");
+        }
+    }
+}
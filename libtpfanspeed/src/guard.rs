@@ -0,0 +1,316 @@
+//! Put the fan back the way it was, however the program ends.
+//!
+//! A [`FanGuard`] remembers the fan level when it is created and restores it
+//! when dropped. Drops don't run on signals or `std::process::exit`, so every
+//! live guard is also kept in a registry that [`restore_all`] empties; call it
+//! from your own signal handler, or let [`install_handlers`] do it on
+//! `SIGINT`, `SIGTERM` and `SIGHUP`. [`install_panic_hook`] does the same on panics.
+//!
+//! Guards go through [`ProcfsBackend`] unless created with another [`FanBackend`],
+//! see [`FanGuard::with_backend`].
+
+use crate::{
+    backend::{FanBackend, ProcfsBackend},
+    error::*,
+    FanSpeed,
+};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex, MutexGuard, Once,
+};
+
+/// A live guard: its id, the level to restore and a copy of its backend to restore it with.
+type Entry = (u64, FanSpeed, Box<dyn FanBackend + Send>);
+
+/// Live guards, oldest first.
+static GUARDS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static PANIC_HOOK: Once = Once::new();
+
+fn guards() -> MutexGuard<'static, Vec<Entry>> {
+    // a panic while holding the lock leaves the list intact, and we need it most then
+    GUARDS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Restores the fan level it was created with when dropped, see the [module docs](self).
+///
+/// ```no_run
+/// # fn main() -> Result<(), libtpfanspeed::error::Error> {
+/// use libtpfanspeed::{guard::FanGuard, FanSpeed};
+///
+/// let mut guard = FanGuard::new()?;
+/// guard.set(FanSpeed::Level(7))?;
+/// // ... run the benchmark ...
+/// guard.restore()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+#[must_use = "the level is restored as soon as the guard is dropped"]
+pub struct FanGuard<B: FanBackend + Clone + Send + 'static = ProcfsBackend> {
+    id: u64,
+    previous: FanSpeed,
+    backend: B,
+    /// Whether dropping restores `previous`, until restored or disarmed.
+    armed: bool,
+}
+
+impl FanGuard {
+    /// Remember the current level.
+    pub fn new() -> Result<Self, Error> {
+        Self::with_backend(ProcfsBackend::default())
+    }
+
+    /// Restore `previous` instead of the current level.
+    pub fn with_previous(previous: FanSpeed) -> Self {
+        Self::with_backend_and_previous(ProcfsBackend::default(), previous)
+    }
+}
+
+impl<B: FanBackend + Clone + Send + 'static> FanGuard<B> {
+    /// Remember the current level of `backend`, and restore it through `backend`.
+    pub fn with_backend(mut backend: B) -> Result<Self, Error> {
+        let previous = backend.status()?.level;
+        Ok(Self::with_backend_and_previous(backend, previous))
+    }
+
+    /// Restore `previous` through `backend`. [`restore_all`] uses a clone of it.
+    pub fn with_backend_and_previous(backend: B, previous: FanSpeed) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        guards().push((id, previous, Box::new(backend.clone())));
+
+        Self {
+            id,
+            previous,
+            backend,
+            armed: true,
+        }
+    }
+
+    /// The level that will be restored.
+    pub fn previous(&self) -> FanSpeed {
+        self.previous
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Set the fan level, to be undone by this guard.
+    pub fn set(&mut self, fs: FanSpeed) -> Result<(), Error> {
+        self.backend.set_level(fs)
+    }
+
+    /// Restore the previous level now, and get to know if it worked.
+    pub fn restore(mut self) -> Result<(), Error> {
+        self.unregister();
+        self.armed = false;
+        self.backend.set_level(self.previous)
+    }
+
+    /// Keep the fan the way it is now.
+    pub fn disarm(mut self) {
+        self.unregister();
+        self.armed = false;
+    }
+
+    /// Take the guard out of the registry. `false` if [`restore_all`] did already.
+    fn unregister(&self) -> bool {
+        let mut guards = guards();
+        let len = guards.len();
+        guards.retain(|(id, _, _)| *id != self.id);
+        guards.len() < len
+    }
+}
+
+impl<B: FanBackend + Clone + Send + 'static> Drop for FanGuard<B> {
+    fn drop(&mut self) {
+        // restore_all put back an older level already
+        if !self.armed || !self.unregister() {
+            return;
+        }
+
+        if let Err(e) = self.backend.set_level(self.previous) {
+            eprintln!("Can't restore the fan level {}: {e}", self.previous);
+        }
+    }
+}
+
+/// Restore the level from before the oldest live guard, and forget every guard,
+/// so dropping them later doesn't change the level again. Does nothing without any.
+pub fn restore_all() -> Result<(), Error> {
+    let oldest = {
+        let mut guards = guards();
+        let oldest = (!guards.is_empty()).then(|| guards.remove(0));
+        guards.clear();
+        oldest
+    };
+
+    match oldest {
+        Some((_, level, mut backend)) => backend.set_level(level),
+        None => Ok(()),
+    }
+}
+
+/// Call [`restore_all`] before the current panic hook runs. Only installs once.
+pub fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            if let Err(e) = restore_all() {
+                eprintln!("Can't restore the fan level: {e}");
+            }
+            hook(info)
+        }));
+    });
+}
+
+/// Call [`restore_all`] and exit on `SIGINT`, `SIGTERM` and `SIGHUP`, and on panics.
+///
+/// There can only be one signal handler per process: if the program has its
+/// own, it should call [`restore_all`] from it instead.
+pub fn install_handlers() -> Result<(), Error> {
+    install_panic_hook();
+
+    ctrlc::set_handler(|| {
+        if let Err(e) = restore_all() {
+            eprintln!("Can't restore the fan level: {e}");
+        }
+        std::process::exit(130);
+    })
+    .map_err(|e| {
+        err!(
+            GenericError,
+            "Call guard::restore_all from the existing handler instead",
+            "Can't install the signal handlers: {}",
+            e
+        )
+        .with_source(e)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::MockBackend, FanStatus};
+    use std::sync::{Arc, MutexGuard};
+
+    /// The registry is global, so tests that use it can't run at the same time.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn serial() -> MutexGuard<'static, ()> {
+        SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// One fan for the guards and the registry alike.
+    #[derive(Debug, Clone, Default)]
+    struct Shared(Arc<Mutex<MockBackend>>);
+
+    impl Shared {
+        fn writes(&self) -> Vec<FanSpeed> {
+            self.0.lock().unwrap().writes().to_vec()
+        }
+    }
+
+    impl FanBackend for Shared {
+        fn status(&mut self) -> Result<FanStatus, Error> {
+            self.0.lock().unwrap().status()
+        }
+
+        fn set_level(&mut self, fs: FanSpeed) -> Result<(), Error> {
+            self.0.lock().unwrap().set_level(fs)
+        }
+
+        fn set_watchdog(&mut self, timeout: u8) -> Result<(), Error> {
+            self.0.lock().unwrap().set_watchdog(timeout)
+        }
+
+        fn enable(&mut self) -> Result<(), Error> {
+            self.0.lock().unwrap().enable()
+        }
+
+        fn disable(&mut self) -> Result<(), Error> {
+            self.0.lock().unwrap().disable()
+        }
+    }
+
+    #[test]
+    fn drop_restores() {
+        let _serial = serial();
+        let fan = Shared::default();
+
+        let mut guard = FanGuard::with_backend(fan.clone()).unwrap();
+        assert_eq!(guard.previous(), FanSpeed::Auto);
+        guard.set(FanSpeed::Level(7)).unwrap();
+        drop(guard);
+
+        assert_eq!(fan.writes(), [FanSpeed::Level(7), FanSpeed::Auto]);
+        assert!(guards().is_empty());
+    }
+
+    #[test]
+    fn restore_and_disarm() {
+        let _serial = serial();
+        let fan = Shared::default();
+
+        let mut guard = FanGuard::with_backend(fan.clone()).unwrap();
+        guard.set(FanSpeed::Level(7)).unwrap();
+        guard.restore().unwrap();
+
+        let mut guard = FanGuard::with_backend(fan.clone()).unwrap();
+        guard.set(FanSpeed::Level(3)).unwrap();
+        guard.disarm();
+
+        assert_eq!(
+            fan.writes(),
+            [FanSpeed::Level(7), FanSpeed::Auto, FanSpeed::Level(3)]
+        );
+        assert!(guards().is_empty());
+    }
+
+    #[test]
+    fn restore_all_restores_the_oldest() {
+        let _serial = serial();
+        let fan = Shared::default();
+
+        let mut first = FanGuard::with_backend(fan.clone()).unwrap();
+        first.set(FanSpeed::Level(5)).unwrap();
+        let mut second = FanGuard::with_backend(fan.clone()).unwrap();
+        second.set(FanSpeed::Level(7)).unwrap();
+        assert_eq!(second.previous(), FanSpeed::Level(5));
+
+        restore_all().unwrap();
+        assert!(guards().is_empty());
+
+        // already restored, dropping them changes nothing
+        drop(second);
+        drop(first);
+        assert_eq!(
+            fan.writes(),
+            [FanSpeed::Level(5), FanSpeed::Level(7), FanSpeed::Auto]
+        );
+
+        // and without any guards, neither does restore_all
+        restore_all().unwrap();
+        assert_eq!(fan.writes().len(), 3);
+    }
+
+    #[test]
+    fn registry_keeps_the_creation_order() {
+        let _serial = serial();
+        let fan = Shared::default();
+
+        let first = FanGuard::with_backend_and_previous(fan.clone(), FanSpeed::Level(1));
+        let second = FanGuard::with_backend_and_previous(fan.clone(), FanSpeed::Level(2));
+        let third = FanGuard::with_backend_and_previous(fan.clone(), FanSpeed::Level(3));
+
+        // the oldest gone, the next one is the oldest
+        first.disarm();
+        restore_all().unwrap();
+        assert_eq!(fan.writes(), [FanSpeed::Level(2)]);
+
+        drop(third);
+        drop(second);
+        assert_eq!(fan.writes(), [FanSpeed::Level(2)]);
+    }
+}
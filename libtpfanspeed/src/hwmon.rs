@@ -0,0 +1,398 @@
+//! Reads sensors straight from `/sys/class/hwmon`, without lm_sensors.
+//!
+//! The readings are laid out exactly like the output of `sensors -j` (chip
+//! name, then label, then `tempN_*` attributes), so every parser that takes a
+//! [`SensorsOutput`] works on either source and gives the same results.
+
+use crate::{error::*, SensorsOutput};
+use serde_json::{Map, Number, Value};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+pub const HWMON_PATH: &str = "/sys/class/hwmon";
+
+/// Build the `sensors -j` equivalent of every chip under [`HWMON_PATH`].
+pub fn get_hwmon_output() -> Result<SensorsOutput, Error> {
+    get_hwmon_output_from(HWMON_PATH)
+}
+
+/// Build the `sensors -j` equivalent of every chip under `root`.
+pub fn get_hwmon_output_from(root: impl AsRef<Path>) -> Result<SensorsOutput, Error> {
+    let root = root.as_ref();
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => {
+                return Err(err!(
+                    FileNotFound,
+                    "Is sysfs mounted?",
+                    "Directory {} not found",
+                    root.display()
+                )
+                .with_source(e))
+            }
+            _ => return Err(generic_err!(e)),
+        },
+    };
+
+    let mut dirs = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect::<Vec<PathBuf>>();
+    // hwmon10 should come after hwmon9
+    dirs.sort_by_key(|dir| hwmon_index(dir));
+
+    let mut chips = Map::new();
+    for dir in dirs {
+        let Some(name) = read_trimmed(&dir.join("name")) else {
+            continue;
+        };
+
+        let features = read_features(&dir);
+        if features.is_empty() {
+            continue;
+        }
+
+        chips.insert(
+            format!("{name}-{}", bus_address(&dir)),
+            Value::Object(features),
+        );
+    }
+
+    Ok(Value::Object(chips).to_string())
+}
+
+/// Find the directory of the chip called `name` (e.g. `thinkpad`) under `root`.
+pub fn find_chip_dir(root: impl AsRef<Path>, name: &str) -> Option<PathBuf> {
+    let mut dirs = fs::read_dir(root)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect::<Vec<PathBuf>>();
+    dirs.sort_by_key(|dir| hwmon_index(dir));
+
+    dirs.into_iter()
+        .find(|dir| read_trimmed(&dir.join("name")).as_deref() == Some(name))
+}
+
+/// Read `fan1_input`, `fan2_input`, ... of a chip, skipping fans that can't be read.
+pub fn read_fan_inputs(dir: impl AsRef<Path>) -> Vec<(u8, u16)> {
+    let dir = dir.as_ref();
+
+    (1..=u8::MAX)
+        .map(|id| (id, dir.join(format!("fan{id}_input"))))
+        .take_while(|(_, path)| path.exists())
+        .filter_map(|(id, path)| Some((id, read_trimmed(&path)?.parse().ok()?)))
+        .collect()
+}
+
+/// Write a single attribute, e.g. `pwm1_enable`.
+pub fn write_attr(dir: impl AsRef<Path>, attr: &str, value: impl ToString) -> Result<(), Error> {
+    let path = dir.as_ref().join(attr);
+
+    match fs::write(&path, value.to_string()) {
+        Ok(()) => Ok(()),
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => Err(err!(
+                FileNotFound,
+                "Did you load thinkpad_acpi?",
+                "File {} not found.",
+                path.display()
+            )
+            .with_source(e)),
+            io::ErrorKind::PermissionDenied => Err(err!(
+                PermissionDenied,
+                "Do you have root permissions? Did you load thinkpad_acpi with fan_control=1?",
+                "while trying to write to {}",
+                path.display()
+            )
+            .with_source(e)),
+            _ => Err(generic_err!(e)),
+        },
+    }
+}
+
+fn hwmon_index(dir: &Path) -> u32 {
+    dir.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix("hwmon"))
+        .and_then(|idx| idx.parse().ok())
+        .unwrap_or(u32::MAX)
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+/// Group `tempN_*`/`fanN_*` attributes under their label, like lm_sensors.
+fn read_features(dir: &Path) -> Map<String, Value> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Map::new();
+    };
+
+    let mut attrs = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter_map(|file| {
+            let (feature, attr) = file.split_once('_')?;
+            let is_sensor = ["temp", "fan"].iter().any(|prefix| {
+                feature
+                    .strip_prefix(prefix)
+                    .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+            });
+
+            (is_sensor && attr != "label").then(|| (feature.to_string(), file.clone()))
+        })
+        .collect::<Vec<(String, String)>>();
+    attrs.sort();
+
+    let mut features = Map::new();
+    for (feature, file) in attrs {
+        // unreadable attributes (e.g. a disconnected sensor) are skipped, as sensors does
+        let Some(raw) = read_trimmed(&dir.join(&file)).and_then(|s| s.parse::<i64>().ok()) else {
+            continue;
+        };
+
+        let label =
+            read_trimmed(&dir.join(format!("{feature}_label"))).unwrap_or_else(|| feature.clone());
+
+        let value = if feature.starts_with("temp") && is_scaled(&file) {
+            Number::from_f64(raw as f64 / 1000.0)
+        } else {
+            Number::from_f64(raw as f64)
+        };
+
+        let Some(value) = value else {
+            continue;
+        };
+
+        if let Value::Object(readings) = features
+            .entry(label)
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            readings.insert(file, Value::Number(value));
+        }
+    }
+
+    features
+}
+
+/// Temperatures are exported in millidegrees, except for flags like alarms.
+fn is_scaled(file: &str) -> bool {
+    !["alarm", "beep", "fault", "type", "enable"]
+        .iter()
+        .any(|suffix| file.ends_with(suffix))
+}
+
+/// Work out the `bus-address` part of a chip name the way libsensors does,
+/// e.g. `isa-0000` for `coretemp.0` or `pci-00c3` for `0000:00:18.3`.
+fn bus_address(dir: &Path) -> String {
+    let Ok(device) = fs::canonicalize(dir.join("device")) else {
+        return "virtual-0".to_string();
+    };
+
+    for path in device.ancestors() {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let subsystem = fs::read_link(path.join("subsystem"))
+            .ok()
+            .and_then(|link| Some(link.file_name()?.to_str()?.to_string()));
+
+        let address = match subsystem.as_deref() {
+            Some("pci") => pci_address(name).map(|addr| format!("pci-{addr:04x}")),
+            Some("platform") => Some(format!("isa-{:04x}", trailing_number(name, '.'))),
+            Some("acpi") => Some(format!("acpi-{:x}", trailing_number(name, ':'))),
+            Some("i2c") => name
+                .split_once('-')
+                .and_then(|(bus, addr)| Some((bus, u16::from_str_radix(addr, 16).ok()?)))
+                .map(|(bus, addr)| format!("i2c-{bus}-{addr:02x}")),
+            _ => None,
+        };
+
+        if let Some(address) = address {
+            return address;
+        }
+    }
+
+    "virtual-0".to_string()
+}
+
+/// `0000:00:18.3` -> `(domain << 16) + (bus << 8) + (slot << 3) + function`
+fn pci_address(name: &str) -> Option<u32> {
+    let mut parts = name.split([':', '.']);
+    let mut next = || u32::from_str_radix(parts.next()?, 16).ok();
+    let (domain, bus, slot, function) = (next()?, next()?, next()?, next()?);
+
+    Some((domain << 16) + (bus << 8) + (slot << 3) + function)
+}
+
+fn trailing_number(name: &str, separator: char) -> u32 {
+    name.rsplit_once(separator)
+        .and_then(|(_, id)| id.parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_sensor_readings_from_sensors_output, get_temps_from_sensors_output};
+    use std::os::unix::fs::symlink;
+
+    /// `sensors -j` on a dual-core Intel ThinkPad.
+    const SENSORS_OUTPUT: &str = r#"{
+        "coretemp-isa-0000": {
+            "Adapter": "ISA adapter",
+            "Package id 0": {
+                "temp1_input": 52.000,
+                "temp1_max": 100.000,
+                "temp1_crit": 105.000,
+                "temp1_crit_alarm": 0.000
+            },
+            "Core 0": {
+                "temp2_input": 50.000,
+                "temp2_max": 100.000,
+                "temp2_crit": 105.000,
+                "temp2_crit_alarm": 0.000
+            },
+            "Core 4": {
+                "temp3_input": 54.000,
+                "temp3_max": 100.000,
+                "temp3_crit": 105.000,
+                "temp3_crit_alarm": 0.000
+            }
+        },
+        "thinkpad-isa-0000": {
+            "Adapter": "ISA adapter",
+            "fan1": {
+                "fan1_input": 2900.000
+            },
+            "CPU": {
+                "temp1_input": 51.000
+            },
+            "GPU": {
+                "temp2_input": 48.000
+            }
+        }
+    }"#;
+
+    /// The same readings as [`SENSORS_OUTPUT`], as sysfs lays them out.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        /// Under a directory of its own for every `test`, as they run in parallel.
+        fn new(test: &str) -> Self {
+            let root = crate::test_dir(&format!("hwmon-{test}"));
+
+            let platform = root.join("bus/platform");
+            fs::create_dir_all(&platform).unwrap();
+
+            let chip = |hwmon: &str, device: &str, name: &str, attrs: &[(&str, &str)]| {
+                let device_dir = root.join("devices/platform").join(device);
+                fs::create_dir_all(&device_dir).unwrap();
+                symlink(&platform, device_dir.join("subsystem")).unwrap();
+
+                let dir = root.join("class/hwmon").join(hwmon);
+                fs::create_dir_all(&dir).unwrap();
+                symlink(&device_dir, dir.join("device")).unwrap();
+                fs::write(dir.join("name"), format!("{name}\n")).unwrap();
+                for (attr, value) in attrs {
+                    fs::write(dir.join(attr), format!("{value}\n")).unwrap();
+                }
+            };
+
+            chip(
+                "hwmon3",
+                "coretemp.0",
+                "coretemp",
+                &[
+                    ("temp1_label", "Package id 0"),
+                    ("temp1_input", "52000"),
+                    ("temp1_max", "100000"),
+                    ("temp1_crit", "105000"),
+                    ("temp1_crit_alarm", "0"),
+                    ("temp2_label", "Core 0"),
+                    ("temp2_input", "50000"),
+                    ("temp2_max", "100000"),
+                    ("temp2_crit", "105000"),
+                    ("temp2_crit_alarm", "0"),
+                    ("temp3_label", "Core 4"),
+                    ("temp3_input", "54000"),
+                    ("temp3_max", "100000"),
+                    ("temp3_crit", "105000"),
+                    ("temp3_crit_alarm", "0"),
+                ],
+            );
+            chip(
+                "hwmon10",
+                "thinkpad_hwmon",
+                "thinkpad",
+                &[
+                    ("fan1_input", "2900"),
+                    ("temp1_label", "CPU"),
+                    ("temp1_input", "51000"),
+                    ("temp2_label", "GPU"),
+                    ("temp2_input", "48000"),
+                ],
+            );
+
+            Self(root)
+        }
+
+        fn hwmon(&self) -> PathBuf {
+            self.0.join("class/hwmon")
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn json(value: impl serde::Serialize) -> Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    #[test]
+    fn same_as_sensors() {
+        let fixture = Fixture::new("same_as_sensors");
+        let hwmon = get_hwmon_output_from(fixture.hwmon()).unwrap();
+
+        assert_eq!(
+            json(get_temps_from_sensors_output(hwmon.clone()).unwrap()),
+            json(get_temps_from_sensors_output(SENSORS_OUTPUT.to_string()).unwrap())
+        );
+        assert_eq!(
+            json(get_sensor_readings_from_sensors_output(hwmon).unwrap()),
+            json(get_sensor_readings_from_sensors_output(SENSORS_OUTPUT.to_string()).unwrap())
+        );
+    }
+
+    #[test]
+    fn chip_names() {
+        let fixture = Fixture::new("chip_names");
+        let hwmon: Map<String, Value> =
+            serde_json::from_str(&get_hwmon_output_from(fixture.hwmon()).unwrap()).unwrap();
+
+        // hwmon10 after hwmon3, like sensors
+        assert_eq!(
+            hwmon.keys().collect::<Vec<_>>(),
+            ["coretemp-isa-0000", "thinkpad-isa-0000"]
+        );
+        assert_eq!(
+            find_chip_dir(fixture.hwmon(), "thinkpad"),
+            Some(fixture.hwmon().join("hwmon10"))
+        );
+        assert_eq!(
+            read_fan_inputs(fixture.hwmon().join("hwmon10")),
+            [(1, 2900)]
+        );
+    }
+
+    #[test]
+    fn pci_addresses() {
+        assert_eq!(pci_address("0000:00:18.3"), Some(0xc3));
+        assert_eq!(pci_address("0000:03:00.0"), Some(0x300));
+        assert_eq!(pci_address("coretemp.0"), None);
+    }
+}
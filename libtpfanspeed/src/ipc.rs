@@ -0,0 +1,360 @@
+//! The protocol spoken over tpfand's Unix socket, and a client for it.
+//!
+//! A client connects, writes one [`Request`] as a line of JSON, and reads one
+//! [`Response`] line back, e.g.
+//!
+//! ```text
+//! > {"command":"set-level","level":7}
+//! < {"type":"ok"}
+//! ```
+//!
+//! The daemon keeps root, so the socket is world-writable: unprivileged users
+//! can control the fan through it without sudo.
+
+use crate::{
+    error::*, failsafe::Trip, profile::Profile, FanReading, FanSpeed, FanStatus, MilliCelsius,
+    SensorReading, Temperatures,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const DEFAULT_SOCKET_PATH: &str = "/run/tpfand.sock";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    /// The CPU temperatures, see [`crate::get_temps`].
+    Temps,
+    /// Every temperature sensor, see [`crate::get_sensor_readings`].
+    Sensors,
+    /// Every fan with its speed.
+    Fans,
+    /// Override the curve with a fixed level, on every fan or only on `fan`,
+    /// until [`Request::Resume`]. With a `duration` in seconds, whatever was in
+    /// control before (the curve, or an earlier level) takes over again after it.
+    /// Without one, the active profile is dropped, see [`crate::profile`].
+    SetLevel {
+        level: FanSpeed,
+        #[serde(default)]
+        fan: Option<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<u64>,
+    },
+    /// Drop the override and go back to the curve.
+    Resume,
+    /// Every profile the daemon knows, see [`crate::config::Config::all_profiles`].
+    Profiles,
+    /// Follow a profile instead of the curve from now on, also after a restart.
+    SetProfile {
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    Status(DaemonStatus),
+    Temps(Temperatures),
+    Sensors(Vec<SensorReading>),
+    Fans(Vec<FanReading>),
+    Profiles(BTreeMap<String, Profile>),
+    Error(Error),
+}
+
+/// What decides the fan level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum ControlMode {
+    /// The curve from the config, or the active profile.
+    Curve,
+    /// A level set through [`Request::SetLevel`].
+    Manual { level: FanSpeed },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub fan: FanStatus,
+    pub control: ControlMode,
+    /// The temperature the curve saw last, if it could be read.
+    pub temp: Option<MilliCelsius>,
+    /// Why the overheat failsafe overrides `control`, if it does.
+    #[serde(default)]
+    pub failsafe: Option<Trip>,
+    /// Seconds left before a timed [`Request::SetLevel`] is over.
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    /// What takes over again once `expires_in` is over.
+    #[serde(default)]
+    pub after_expiry: Option<ControlMode>,
+    /// The profile followed instead of the curve, if any.
+    #[serde(default)]
+    pub profile: Option<String>,
+}
+
+impl std::fmt::Display for ControlMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Curve => write!(f, "curve"),
+            Self::Manual { level } => write!(f, "manual ({level})"),
+        }
+    }
+}
+
+/// Talks to a running tpfand. Every request uses a new connection.
+#[derive(Debug, Clone)]
+pub struct Client {
+    path: PathBuf,
+}
+
+impl Client {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// A client for the daemon listening on `path`, if there is one.
+    ///
+    /// A socket file left behind by a daemon that was killed doesn't count.
+    pub fn connect(path: impl AsRef<Path>) -> Option<Self> {
+        let path = path.as_ref();
+        UnixStream::connect(path).ok()?;
+
+        Some(Self::new(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Send `request`, turning [`Response::Error`] into an `Err`.
+    pub fn request(&self, request: &Request) -> Result<Response, Error> {
+        let mut stream = UnixStream::connect(&self.path).map_err(|e| self.io_err(e))?;
+        stream
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(TIMEOUT)))
+            .map_err(|e| self.io_err(e))?;
+
+        let mut line = serde_json::to_string(request).map_err(|e| generic_err!(e))?;
+        line.push('\n');
+        stream
+            .write_all(line.as_bytes())
+            .map_err(|e| self.io_err(e))?;
+
+        let mut line = String::new();
+        BufReader::new(stream)
+            .read_line(&mut line)
+            .map_err(|e| self.io_err(e))?;
+
+        match serde_json::from_str(&line) {
+            Ok(Response::Error(e)) => Err(e),
+            Ok(response) => Ok(response),
+            Err(e) => Err(err!(
+                Ipc,
+                "Are tpfand and tpfanctl the same version?",
+                "Malformed response from tpfand: {}",
+                e
+            )
+            .with_source(e)),
+        }
+    }
+
+    pub fn status(&self) -> Result<DaemonStatus, Error> {
+        match self.request(&Request::Status)? {
+            Response::Status(status) => Ok(status),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    pub fn temps(&self) -> Result<Temperatures, Error> {
+        match self.request(&Request::Temps)? {
+            Response::Temps(temps) => Ok(temps),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    pub fn sensors(&self) -> Result<Vec<SensorReading>, Error> {
+        match self.request(&Request::Sensors)? {
+            Response::Sensors(readings) => Ok(readings),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    pub fn fans(&self) -> Result<Vec<FanReading>, Error> {
+        match self.request(&Request::Fans)? {
+            Response::Fans(fans) => Ok(fans),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    pub fn set_level(&self, level: FanSpeed, fan: Option<u8>) -> Result<(), Error> {
+        self.expect_ok(&Request::SetLevel {
+            level,
+            fan,
+            duration: None,
+        })
+    }
+
+    /// Like [`Client::set_level`], but only for `duration` (rounded up to a second).
+    pub fn set_level_timed(
+        &self,
+        level: FanSpeed,
+        fan: Option<u8>,
+        duration: Duration,
+    ) -> Result<(), Error> {
+        let secs = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+
+        self.expect_ok(&Request::SetLevel {
+            level,
+            fan,
+            duration: Some(secs),
+        })
+    }
+
+    pub fn resume(&self) -> Result<(), Error> {
+        self.expect_ok(&Request::Resume)
+    }
+
+    pub fn profiles(&self) -> Result<BTreeMap<String, Profile>, Error> {
+        match self.request(&Request::Profiles)? {
+            Response::Profiles(profiles) => Ok(profiles),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    pub fn set_profile(&self, name: &str) -> Result<(), Error> {
+        self.expect_ok(&Request::SetProfile {
+            name: name.to_string(),
+        })
+    }
+
+    fn expect_ok(&self, request: &Request) -> Result<(), Error> {
+        match self.request(request)? {
+            Response::Ok => Ok(()),
+            response => Err(Self::unexpected(response)),
+        }
+    }
+
+    fn unexpected(response: Response) -> Error {
+        err!(
+            Ipc,
+            "Are tpfand and tpfanctl the same version?",
+            "Unexpected response from tpfand: {:?}",
+            response
+        )
+    }
+
+    fn io_err(&self, e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => err!(
+                PermissionDenied,
+                "Do you have sufficient permissions?",
+                "while trying to connect to {}",
+                self.path.display()
+            )
+            .with_source(e),
+            _ => err!(
+                Ipc,
+                "Is tpfand still running?",
+                "{}: {}",
+                self.path.display(),
+                e
+            )
+            .with_source(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_round_trip() {
+        for request in [
+            Request::Status,
+            Request::Temps,
+            Request::Sensors,
+            Request::Fans,
+            Request::SetLevel {
+                level: FanSpeed::Level(7),
+                fan: None,
+                duration: None,
+            },
+            Request::SetLevel {
+                level: FanSpeed::FullSpeed,
+                fan: Some(2),
+                duration: Some(60),
+            },
+            Request::Resume,
+            Request::Profiles,
+            Request::SetProfile {
+                name: "quiet".to_string(),
+            },
+        ] {
+            let json = serde_json::to_string(&request).unwrap();
+            assert_eq!(
+                serde_json::from_str::<Request>(&json).unwrap(),
+                request,
+                "{json}"
+            );
+        }
+
+        // as in the module docs, for clients written by hand
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"command":"set-level","level":7}"#).unwrap(),
+            Request::SetLevel {
+                level: FanSpeed::Level(7),
+                fan: None,
+                duration: None,
+            }
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"command":"set-level","level":0}"#).is_err());
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let status = DaemonStatus {
+            fan: FanStatus {
+                enabled: true,
+                speed: 2900,
+                level: FanSpeed::Level(0),
+                commands: Vec::new(),
+                watchdog: Some(0),
+            },
+            control: ControlMode::Manual {
+                level: FanSpeed::Level(5),
+            },
+            temp: Some(MilliCelsius(61_500)),
+            failsafe: None,
+            expires_in: Some(30),
+            after_expiry: Some(ControlMode::Curve),
+            profile: Some("quiet".to_string()),
+        };
+
+        for response in [
+            Response::Ok,
+            Response::Status(status),
+            Response::Fans(vec![FanReading { id: 1, rpm: 2900 }]),
+            Response::Profiles(crate::profile::builtin()),
+            Response::Error(err!(InvalidValue, "Try 1-7", "9 is too fast")),
+        ] {
+            let json = serde_json::to_string(&response).unwrap();
+            let again = serde_json::from_str::<Response>(&json).unwrap();
+            assert_eq!(serde_json::to_string(&again).unwrap(), json);
+        }
+
+        assert!(matches!(
+            serde_json::from_str(r#"{"type":"ok"}"#).unwrap(),
+            Response::Ok
+        ));
+    }
+}
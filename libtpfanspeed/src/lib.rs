@@ -1,30 +1,97 @@
+pub mod backend;
+pub mod config;
+pub mod curve;
 pub mod error;
-
+pub mod failsafe;
+pub mod guard;
+pub mod hwmon;
+pub mod ipc;
+pub mod profile;
+pub mod topology;
+
+use backend::{FanBackend, ProcfsBackend};
 use error::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{
-    collections::BTreeMap,
-    fs::OpenOptions,
-    io::{self, Read, Write},
-};
+use std::{collections::BTreeMap, io};
 
 // Represents the output of `sensors -j`. Type alias for clarity.
 pub type SensorsOutput = String;
 
-#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+// Represents the contents of `/proc/acpi/ibm/fan`. Type alias for clarity.
+pub type ProcFanOutput = String;
+
+pub const PROC_FAN_PATH: &str = "/proc/acpi/ibm/fan";
+pub const FAN_WATCHDOG_PATH: &str = "/sys/bus/platform/drivers/thinkpad_hwmon/fan_watchdog";
+pub const MAX_WATCHDOG_TIMEOUT: u8 = 120;
+
+// k10temp and zenpower don't export any limits, so assume those of Ryzen Mobile CPUs (Tjmax 105°C).
+const AMD_DEFAULT_MAX: MilliCelsius = MilliCelsius(95_000);
+const AMD_DEFAULT_CRIT: MilliCelsius = MilliCelsius(105_000);
+// only used if coretemp doesn't export the limits, which recent kernels always do.
+const INTEL_DEFAULT_TJMAX: MilliCelsius = MilliCelsius(100_000);
+
+/// A temperature in millidegrees Celsius, the unit hwmon reports in.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct MilliCelsius(pub i32);
+
+/// The kind of core on hybrid CPUs (e.g. Alder Lake and newer).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CoreType {
+    #[default]
+    Unknown,
+    Performance,
+    Efficiency,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct CoreTemperature {
-    pub temp: u8,
-    pub max: u8,
-    pub critical: u8,
+    pub temp: MilliCelsius,
+    pub max: MilliCelsius,
+    pub critical: MilliCelsius,
+    /// The label reported by the kernel, e.g. `Core 4` or `Tccd1`.
+    #[serde(default)]
+    pub label: String,
+    #[serde(default)]
+    pub core_type: CoreType,
 }
 
+/// The readings of one CPU package (socket).
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
-pub struct Temperatures {
-    pub avg: u8,
+pub struct PackageTemperatures {
+    /// `Package id N` on Intel and `Tctl` on AMD, or the average of the cores if not reported.
+    pub temp: Option<MilliCelsius>,
     pub cores: BTreeMap<u8, CoreTemperature>,
+    /// Per-die readings (`Tccd1`, `Tccd2`, ...) on AMD CPUs, which don't report per-core temperatures.
+    pub dies: BTreeMap<u8, CoreTemperature>,
 }
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Temperatures {
+    /// The average of all package temperatures.
+    pub avg: MilliCelsius,
+    pub packages: BTreeMap<u8, PackageTemperatures>,
+}
+
+/// Any temperature sensor, not just CPU cores (thinkpad_acpi, GPU, NVMe, ACPI thermal zones...).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SensorReading {
+    /// The chip the sensor belongs to, e.g. `thinkpad-isa-0000` or `nvme-pci-0400`.
+    pub chip: String,
+    /// The sensor label, e.g. `GPU` or `Composite`.
+    pub label: String,
+    pub temp: MilliCelsius,
+    pub max: Option<MilliCelsius>,
+    pub critical: Option<MilliCelsius>,
+}
+
+/// A fan level as thinkpad_acpi knows it. Serialized like it is written to
+/// procfs: levels as numbers, everything else as `auto`, `full-speed` or
+/// `disengaged`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FanSpeed {
     Level(u8),
     FullSpeed,
@@ -32,7 +99,40 @@ pub enum FanSpeed {
     Auto,
 }
 
-const VALID_SPEEDS: &str = "Valid fan speeds range from 0-7, auto, full-speed and disengaged";
+/// The parsed contents of `/proc/acpi/ibm/fan`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FanStatus {
+    pub enabled: bool,
+    /// The fan speed in RPM.
+    pub speed: u16,
+    #[serde(deserialize_with = "FanSpeed::deserialize_reading")]
+    pub level: FanSpeed,
+    /// The `commands:` lines, only present if thinkpad_acpi was loaded with `fan_control=1`.
+    pub commands: Vec<String>,
+    /// The firmware watchdog timeout in seconds (0 is off), if it could be read.
+    #[serde(default)]
+    pub watchdog: Option<u8>,
+}
+
+/// The speed of one fan. Fans are numbered from 1, like `fanN_input` in hwmon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FanReading {
+    pub id: u8,
+    pub rpm: u16,
+}
+
+/// Where temperature readings come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TempSource {
+    /// Read `/sys/class/hwmon` directly.
+    #[default]
+    Hwmon,
+    /// Run `sensors -j` from lm_sensors.
+    Sensors,
+}
+
+const VALID_SPEEDS: &str = "Valid fan speeds range from 1-7, auto, full-speed and disengaged";
 
 impl std::fmt::Display for FanSpeed {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -49,6 +149,41 @@ impl std::fmt::Display for FanSpeed {
     }
 }
 
+impl std::fmt::Display for FanReading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fan {}: {} RPM", self.id, self.rpm)
+    }
+}
+
+impl std::fmt::Display for TempSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            TempSource::Hwmon => "hwmon",
+            TempSource::Sensors => "sensors",
+        };
+
+        write!(f, "{s}")
+    }
+}
+
+impl std::fmt::Display for MilliCelsius {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.1}", self.as_celsius())
+    }
+}
+
+impl std::fmt::Display for CoreType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            CoreType::Unknown => "unknown",
+            CoreType::Performance => "P-core",
+            CoreType::Efficiency => "E-core",
+        };
+
+        write!(f, "{s}")
+    }
+}
+
 impl std::fmt::Display for CoreTemperature {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -61,25 +196,74 @@ impl std::fmt::Display for CoreTemperature {
 
 impl std::fmt::Display for Temperatures {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Average: {}°C", self.avg)?;
+        write!(f, "Average: {}°C", self.avg)?;
+
+        for (package_id, package) in &self.packages {
+            write!(f, "\nPackage {package_id}: {package}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for SensorReading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}°C", self.chip, self.label, self.temp)?;
+
+        match (self.max, self.critical) {
+            (Some(max), Some(crit)) => write!(f, " (max {max}, crit {crit})"),
+            (Some(max), None) => write!(f, " (max {max})"),
+            (None, Some(crit)) => write!(f, " (crit {crit})"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for PackageTemperatures {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(temp) = self.temp {
+            write!(f, "{temp}°C")?;
+        }
 
-        for (i, (corename, coretemp)) in self.cores.iter().enumerate() {
-            write!(f, "{}: {}", corename, coretemp)?;
+        for (corename, coretemp) in &self.cores {
+            write!(f, "\n  {}: {}", corename, coretemp)?;
 
-            if i != self.cores.len() - 1 {
-                writeln!(f)?;
+            if coretemp.core_type != CoreType::Unknown {
+                write!(f, " [{}]", coretemp.core_type)?;
             }
         }
+
+        for (die, dietemp) in &self.dies {
+            write!(f, "\n  CCD {}: {}", die, dietemp)?;
+        }
+
         Ok(())
     }
 }
 
+impl MilliCelsius {
+    pub fn from_celsius(celsius: f64) -> Self {
+        Self((celsius * 1000.0).round() as i32)
+    }
+
+    pub fn as_celsius(&self) -> f64 {
+        self.0 as f64 / 1000.0
+    }
+
+    /// Rounded to whole degrees.
+    pub fn degrees(&self) -> i32 {
+        (self.0 as f64 / 1000.0).round() as i32
+    }
+}
+
 impl CoreTemperature {
-    pub fn new(temp: u8, max: u8, critical: u8) -> Self {
+    pub fn new(temp: MilliCelsius, max: MilliCelsius, critical: MilliCelsius) -> Self {
         Self {
             temp,
             max,
             critical,
+            label: String::new(),
+            core_type: CoreType::Unknown,
         }
     }
 }
@@ -87,13 +271,134 @@ impl CoreTemperature {
 impl Temperatures {
     pub fn new() -> Self {
         Self {
-            avg: 0,
-            cores: BTreeMap::new(),
+            avg: MilliCelsius::default(),
+            packages: BTreeMap::new(),
         }
     }
+
+    /// Every core on every package, as `(package id, core id, reading)`.
+    pub fn cores(&self) -> impl Iterator<Item = (u8, u8, &CoreTemperature)> {
+        self.packages.iter().flat_map(|(package_id, package)| {
+            package
+                .cores
+                .iter()
+                .map(move |(core_id, core)| (*package_id, *core_id, core))
+        })
+    }
+}
+
+impl FanStatus {
+    /// Whether the fan speed can be set, i.e. the `level` command is advertised.
+    pub fn is_controllable(&self) -> bool {
+        self.supports("level")
+    }
+
+    /// Whether a command (`level`, `watchdog`, `enable` or `disable`) is advertised.
+    pub fn supports(&self, command: &str) -> bool {
+        // e.g. `level <level> (...)` or `enable, disable`
+        self.commands.iter().any(|c| {
+            c.split(',')
+                .any(|part| part.split_whitespace().next() == Some(command))
+        })
+    }
+}
+
+impl TempSource {
+    pub fn from_string(value: String) -> Result<Self, Error> {
+        match value.as_ref() {
+            "hwmon" => Ok(Self::Hwmon),
+            "sensors" => Ok(Self::Sensors),
+            _ => Err(err!(
+                InvalidValue,
+                "Valid temperature sources are hwmon and sensors",
+                "{} is an invalid temperature source",
+                value
+            )),
+        }
+    }
+}
+
+impl Serialize for FanSpeed {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Level(level) => serializer.serialize_u8(*level),
+            _ => serializer.collect_str(self),
+        }
+    }
+}
+
+/// A fan level as a number or a name, as a string for [`FanSpeed::from_string`].
+fn deserialize_fanspeed_str<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Level(u8),
+        Name(String),
+    }
+
+    Ok(match Raw::deserialize(deserializer)? {
+        Raw::Level(level) => level.to_string(),
+        Raw::Name(name) => name,
+    })
+}
+
+impl<'de> Deserialize<'de> for FanSpeed {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Self::from_string(deserialize_fanspeed_str(deserializer)?).map_err(serde::de::Error::custom)
+    }
 }
 
 impl FanSpeed {
+    /// Map a PWM duty cycle (0-255) to the level thinkpad_acpi sets for it.
+    ///
+    /// The EC only knows levels, so the driver scales `pwm1` down to
+    /// `pwm / 32`: 32-63 is level 1, ..., 224-255 level 7. 0-31 would be
+    /// level 0, which stops the fan, so they map to level 1 too.
+    pub fn from_pwm(pwm: u8) -> Self {
+        Self::Level((pwm >> 5).max(1))
+    }
+
+    /// Map a level to the PWM duty cycle thinkpad_acpi reports for it
+    /// (`level * 255 / 7`). `auto` has no fixed duty cycle.
+    pub fn to_pwm(&self) -> Option<u8> {
+        match self {
+            Self::Level(level) => Some((*level.min(&7) as u16 * 255 / 7) as u8),
+            Self::FullSpeed | Self::Disengaged => Some(u8::MAX),
+            Self::Auto => None,
+        }
+    }
+
+    /// Whether the fan runs the same at both levels. thinkpad_acpi reads
+    /// `full-speed` back as `disengaged`, so compare levels read from procfs with this.
+    pub fn is_same_as(&self, other: FanSpeed) -> bool {
+        matches!(
+            (self, other),
+            (
+                Self::FullSpeed | Self::Disengaged,
+                Self::FullSpeed | Self::Disengaged
+            )
+        ) || *self == other
+    }
+
+    /// Like [`Self::from_string`], but also level 0. tpfanspeed never sets it,
+    /// as it stops the fan, but thinkpad_acpi reports it (e.g. after a low `pwm1`).
+    pub fn from_reading(value: String) -> Result<Self, Error> {
+        match value.as_ref() {
+            "0" => Ok(Self::Level(0)),
+            _ => Self::from_string(value),
+        }
+    }
+
+    /// For `deserialize_with` on levels read back from the fan, see [`Self::from_reading`].
+    pub fn deserialize_reading<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        Self::from_reading(deserialize_fanspeed_str(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+
     pub fn from_string(value: String) -> Result<Self, Error> {
         let parsed = value.parse::<u8>();
 
@@ -141,132 +446,135 @@ impl FanSpeed {
     }
 }
 
-pub fn set_fanspeed(fs: FanSpeed) -> Result<(), Error> {
-    let err = OpenOptions::new()
-        .append(true)
-        .read(true)
-        .open("/proc/acpi/ibm/fan");
+pub fn get_fan_status_from_proc_output(proc_output: ProcFanOutput) -> Result<FanStatus, Error> {
+    let mut enabled = None;
+    let mut speed = None;
+    let mut level = None;
+    let mut commands = Vec::new();
 
-    let mut file = match err {
-        Err(e) => match e.kind() {
-            io::ErrorKind::NotFound => {
-                return Err(err!(
-                    FileNotFound,
-                    "Did you load thinkpad_acpi?",
-                    "File not /proc/acpi/ibm/fan not found."
-                ))
+    for line in proc_output.lines() {
+        // blank lines are harmless, anything else must be a `key: value` pair
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (key, value) = line.split_once(':').ok_or_else(|| {
+            err!(
+                MalformedProcfs,
+                "Is this a ThinkPad with thinkpad_acpi loaded?",
+                "Expected a `key: value` pair in {}, got `{}`",
+                PROC_FAN_PATH,
+                line
+            )
+        })?;
+        let value = value.trim();
+
+        match key.trim() {
+            "status" => {
+                enabled = match value {
+                    "enabled" => Some(true),
+                    "disabled" => Some(false),
+                    _ => {
+                        return Err(err!(
+                            MalformedProcfs,
+                            "Is this a ThinkPad with thinkpad_acpi loaded?",
+                            "Unknown fan status `{}` in {}",
+                            value,
+                            PROC_FAN_PATH
+                        ))
+                    }
+                }
             }
-            io::ErrorKind::PermissionDenied => {
-                return Err(err!(
-                    PermissionDenied,
-                    "Do you have root permissions?",
-                    "while trying to write to /proc/acpi/ibm/fan"
-                ))
+            "speed" => {
+                speed = Some(value.parse::<u16>().map_err(|e| {
+                    err!(
+                        MalformedProcfs,
+                        "Is this a ThinkPad with thinkpad_acpi loaded?",
+                        "Fan speed `{}` in {} is not a valid RPM",
+                        value,
+                        PROC_FAN_PATH
+                    )
+                    .with_source(e)
+                })?)
             }
-            _ => return Err(generic_err!(e)),
-        },
-        Ok(f) => f,
-    };
+            "level" => {
+                level = Some(FanSpeed::from_reading(value.to_string()).map_err(|e| {
+                    err!(
+                        MalformedProcfs,
+                        VALID_SPEEDS,
+                        "Fan level `{}` in {} is not a valid fan speed",
+                        value,
+                        PROC_FAN_PATH
+                    )
+                    .with_source(e)
+                })?)
+            }
+            "commands" => commands.push(value.to_string()),
+            // newer kernels may add more keys, which we don't care about
+            _ => (),
+        }
+    }
 
-    let mut s = String::new();
-    match file.read_to_string(&mut s) {
-        Ok(_) => (),
-        Err(e) => return Err(generic_err!(e)),
+    let missing = |key: &str| {
+        err!(
+            MalformedProcfs,
+            "Did you load thinkpad_acpi?",
+            "Key `{}` missing from {}",
+            key,
+            PROC_FAN_PATH
+        )
     };
 
-    if !s.contains("command") {
-        Err(err!(
-            FanControlDisabled,
-            "Did you load thinkpad_acpi with fan_control=1?",
-            "Can't control the fan speed"
-        ))
-    } else {
-        let err = file.write(format!("level {fs}").as_bytes());
-
-        match err {
-            Ok(_) => Ok(()),
-            Err(e) => match e.kind() {
-                io::ErrorKind::InvalidInput => Err(err!(
-                    FanControlDisabled,
-                    "Did you load thinkpad_acpi with fan_control=1",
-                    "Can't control the fan speed. "
-                )),
-                _ => panic!("{e}"),
-            },
-        }
-    }
+    Ok(FanStatus {
+        enabled: enabled.ok_or_else(|| missing("status"))?,
+        speed: speed.ok_or_else(|| missing("speed"))?,
+        level: level.ok_or_else(|| missing("level"))?,
+        commands,
+        watchdog: None,
+    })
 }
 
-pub fn get_rpm() -> Result<u16, Error> {
-    let err = OpenOptions::new().read(true).open("/proc/acpi/ibm/fan");
+pub fn get_fan_status() -> Result<FanStatus, Error> {
+    ProcfsBackend::default().status()
+}
 
-    let mut file = match err {
-        Err(e) => match e.kind() {
-            io::ErrorKind::NotFound => {
-                return Err(err!(
-                    FileNotFound,
-                    "Did you load thinkpad_acpi?",
-                    "File not /proc/acpi/ibm/fan not found"
-                ))
-            }
-            io::ErrorKind::PermissionDenied => {
-                return Err(err!(
-                    PermissionDenied,
-                    "Do you have sufficient permissions?",
-                    "while trying to read from /proc/acpi/ibm/fan"
-                ))
-            }
-            _ => return Err(generic_err!(e)),
-        },
-        Ok(f) => f,
-    };
+pub fn set_fanspeed(fs: FanSpeed) -> Result<(), Error> {
+    ProcfsBackend::default().set_level(fs)
+}
 
-    let mut s = String::new();
-    let _ = file.read_to_string(&mut s);
+pub fn get_rpm() -> Result<u16, Error> {
+    ProcfsBackend::default().rpm()
+}
 
-    let rpm = s.split('\n').collect::<Vec<&str>>()[1] // get second line
-        .split_once(':')
-        .unwrap()
-        .1
-        .trim();
+/// Arm the firmware watchdog, see [`FanBackend::set_watchdog`].
+pub fn set_watchdog(timeout: u8) -> Result<(), Error> {
+    ProcfsBackend::default().set_watchdog(timeout)
+}
 
-    Ok(rpm.parse::<u16>().expect("Failed to parse RPM"))
+pub fn enable_fan() -> Result<(), Error> {
+    ProcfsBackend::default().enable()
 }
 
-pub fn get_fanspeed() -> Result<String, Error> {
-    let err = OpenOptions::new().read(true).open("/proc/acpi/ibm/fan");
+pub fn disable_fan() -> Result<(), Error> {
+    ProcfsBackend::default().disable()
+}
 
-    let mut file = match err {
-        Err(e) => match e.kind() {
-            io::ErrorKind::NotFound => {
-                return Err(err!(
-                    FileNotFound,
-                    "Did you load thinkpad_acpi?",
-                    "File not /proc/acpi/ibm/fan not found"
-                ))
-            }
-            io::ErrorKind::PermissionDenied => {
-                return Err(err!(
-                    PermissionDenied,
-                    "Do you have sufficient permissions?",
-                    "while trying to read from /proc/acpi/ibm/fan"
-                ))
-            }
-            _ => return Err(generic_err!(e)),
-        },
-        Ok(f) => f,
-    };
+/// Set a PWM duty cycle, see [`FanBackend::set_pwm`].
+pub fn set_pwm(pwm: u8) -> Result<(), Error> {
+    ProcfsBackend::default().set_pwm(pwm)
+}
 
-    let mut s = String::new();
-    let _ = file.read_to_string(&mut s);
+/// Set the level of a single fan, see [`FanBackend::set_fan_level`].
+pub fn set_fanspeed_for(fan: u8, fs: FanSpeed) -> Result<(), Error> {
+    ProcfsBackend::default().set_fan_level(fan, fs)
+}
 
-    let fanspeed = s.split('\n').collect::<Vec<&str>>()[2] // get third line
-        .split_once(':')
-        .unwrap()
-        .1
-        .trim();
+pub fn get_fans() -> Result<Vec<FanReading>, Error> {
+    ProcfsBackend::default().fans()
+}
 
-    Ok(fanspeed.into())
+pub fn get_fanspeed() -> Result<FanSpeed, Error> {
+    Ok(get_fan_status()?.level)
 }
 
 pub fn get_sensors_output() -> Result<SensorsOutput, Error> {
@@ -279,88 +587,212 @@ pub fn get_sensors_output() -> Result<SensorsOutput, Error> {
                     FileNotFound,
                     "Do you have lm_sensors installed?",
                     "Could not access sensors command"
-                ))
+                )
+                .with_source(e))
             }
-            _ => panic!("{}", e),
+            _ => return Err(generic_err!(e)),
         },
     };
 
+    if !output.status.success() {
+        return Err(err!(
+            NoReadings,
+            "Run `sensors -j` to see what went wrong",
+            "sensors failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
     Ok(String::from_utf8_lossy(&output.stdout).to_string())
 }
 
-pub fn get_temps_from_sensors_output(sensors_output: SensorsOutput) -> Result<Temperatures, Error> {
-    let mut res = Temperatures::new();
-    let json_value: serde_json::Value =
-        serde_json::from_str(&sensors_output).expect("failed to parse JSON");
+/// Get readings in the `sensors -j` format from either source.
+pub fn get_sensors_output_from(source: TempSource) -> Result<SensorsOutput, Error> {
+    match source {
+        TempSource::Hwmon => hwmon::get_hwmon_output(),
+        TempSource::Sensors => get_sensors_output(),
+    }
+}
+
+/// Parse `sensors -j` output into its chips.
+fn parse_sensors_output(sensors_output: &str) -> Result<serde_json::Map<String, Value>, Error> {
+    const HELP: &str = "Run `sensors -j` and check its output";
+
+    match serde_json::from_str(sensors_output) {
+        Ok(Value::Object(chips)) => Ok(chips),
+        Ok(_) => Err(err!(
+            MalformedSensorJson,
+            HELP,
+            "Expected an object of chips"
+        )),
+        Err(e) => Err(err!(MalformedSensorJson, HELP, "{}", e).with_source(e)),
+    }
+}
+
+/// Find the `tempN_<attr>` reading of a feature, whatever N is.
+fn get_feature_attr(feature: &Value, attr: &str) -> Option<f64> {
+    feature
+        .as_object()?
+        .iter()
+        .find(|(key, _)| {
+            key.strip_prefix("temp")
+                .and_then(|key| key.split_once('_'))
+                .is_some_and(|(n, a)| a == attr && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .and_then(|(_, value)| value.as_f64())
+}
+
+/// Build a reading from whichever `tempN_input`/`_max`/`_crit` a feature contains.
+fn get_coretemp(
+    label: &str,
+    feature: &Value,
+    default_max: MilliCelsius,
+    default_crit: MilliCelsius,
+) -> Option<CoreTemperature> {
+    let temp = get_feature_attr(feature, "input")?;
+
+    Some(CoreTemperature {
+        label: label.to_string(),
+        ..CoreTemperature::new(
+            MilliCelsius::from_celsius(temp),
+            get_feature_attr(feature, "max").map_or(default_max, MilliCelsius::from_celsius),
+            get_feature_attr(feature, "crit").map_or(default_crit, MilliCelsius::from_celsius),
+        )
+    })
+}
 
-    // get individual core temperatures
-    let coretemps = json_value
-        .get("coretemp-isa-0000")
-        .expect("failed to find key")
-        .as_object()
-        .expect("failed to find key");
+/// Average in a wider type so that many hot cores can't overflow.
+fn average(temps: impl Iterator<Item = MilliCelsius>) -> Result<MilliCelsius, Error> {
+    let (sum, count) = temps.fold((0i64, 0i64), |(sum, count), t| {
+        (sum + t.0 as i64, count + 1)
+    });
+
+    if count == 0 {
+        return Err(err!(
+            NoReadings,
+            "Is the coretemp, k10temp or zenpower module loaded?",
+            "No temperatures to average"
+        ));
+    }
+
+    Ok(MilliCelsius((sum / count) as i32))
+}
 
-    for (key, value) in coretemps {
-        let k = key.as_str();
+/// Parse a `coretemp-isa-XXXX` chip, returning its package id and readings.
+fn get_intel_package(
+    chip_address: &str,
+    chip: &serde_json::Map<String, Value>,
+) -> Option<(u8, PackageTemperatures)> {
+    let mut res = PackageTemperatures::default();
+    let mut package = None;
+
+    for (key, value) in chip {
+        if let Some(package_id) = key
+            .strip_prefix("Package id ")
+            .and_then(|id| id.parse::<u8>().ok())
+        {
+            package = Some(package_id);
+            res.temp = get_feature_attr(value, "input").map(MilliCelsius::from_celsius);
+            continue;
+        }
 
         // ignore other data
-        if !k.contains("Core ") {
+        let Some(core_id) = key
+            .strip_prefix("Core ")
+            .and_then(|id| id.parse::<u8>().ok())
+        else {
             continue;
+        };
+
+        // core IDs are sparse on hybrid CPUs, so don't guess which tempN belongs to which core
+        if let Some(coretemp) = get_coretemp(key, value, INTEL_DEFAULT_TJMAX, INTEL_DEFAULT_TJMAX) {
+            res.cores.insert(core_id, coretemp);
         }
+    }
 
-        let coreid = k
-            .split_once(' ')
-            .expect("expected Core and an ID")
-            .1
-            .parse::<u8>()
-            .expect("ID should be a valid number");
-
-        let temp = value
-            .get(format!("temp{}_input", coreid + 2))
-            .expect("core id somehow invalid?")
-            .as_f64()
-            .expect("expected a float, got something else") as u8;
-
-        let max = value
-            .get(format!("temp{}_max", coreid + 2))
-            .expect("core id somehow invalid?")
-            .as_f64()
-            .expect("expected a float, got something else") as u8;
-
-        let critical = value
-            .get(format!("temp{}_crit", coreid + 2))
-            .expect("core id somehow invalid?")
-            .as_f64()
-            .expect("expected a float, got something else") as u8;
-
-        let coretemp = CoreTemperature::new(temp, max, critical);
-
-        let core_id = k
-            .split_whitespace()
-            .nth(1)
-            .and_then(|temp| temp.parse::<u8>().ok())
-            .expect("something is seriously wrong. the core id doesn't begin with core!");
-
-        res.cores.insert(core_id, coretemp);
-    }
-
-    // get average temperature if `Package id 0` is a key
-    if let Some(package_id_0) = json_value
-        .get("coretemp-isa-0000")
-        .expect("failed to find key")
-        .get("Package id 0")
-    {
-        res.avg = package_id_0
-            .get("temp1_input")
-            .expect("failed to find key")
-            .as_f64()
-            .expect("expected a float, got something else") as u8;
+    if res.cores.is_empty() && res.temp.is_none() {
+        return None;
+    }
+
+    // older CPUs don't report `Package id N`, fall back to the ISA address (one per socket)
+    let package = package
+        .or_else(|| u8::from_str_radix(chip_address.rsplit('-').next()?, 16).ok())
+        .unwrap_or(0);
+
+    Some((package, res))
+}
+
+/// Parse a k10temp or zenpower chip. There is one chip per socket.
+fn get_amd_package(chip: &serde_json::Map<String, Value>) -> Option<PackageTemperatures> {
+    let mut res = PackageTemperatures::default();
+
+    for (key, value) in chip {
+        let Some(die) = key
+            .strip_prefix("Tccd")
+            .and_then(|id| id.parse::<u8>().ok())
+        else {
+            continue;
+        };
+
+        if let Some(dietemp) = get_coretemp(key, value, AMD_DEFAULT_MAX, AMD_DEFAULT_CRIT) {
+            res.dies.insert(die, dietemp);
+        }
+    }
+
+    // Tctl is what the CPU itself (and thus the EC) regulates on
+    res.temp = chip
+        .get("Tctl")
+        .and_then(|tctl| get_feature_attr(tctl, "input"))
+        .map(MilliCelsius::from_celsius);
+
+    if res.dies.is_empty() && res.temp.is_none() {
+        None
     } else {
-        // manually calculate average
-        let temps_sum = res.cores.iter().map(|(_, v)| v.temp).sum::<u8>();
-        res.avg = temps_sum / res.cores.len() as u8;
+        Some(res)
+    }
+}
+
+pub fn get_temps_from_sensors_output(sensors_output: SensorsOutput) -> Result<Temperatures, Error> {
+    let mut res = Temperatures::new();
+    let chips = parse_sensors_output(&sensors_output)?;
+
+    let mut amd_packages = 0;
+    for (name, chip) in &chips {
+        let Some(chip) = chip.as_object() else {
+            continue;
+        };
+
+        if let Some(address) = name.strip_prefix("coretemp-") {
+            if let Some((id, package)) = get_intel_package(address, chip) {
+                res.packages.insert(id, package);
+            }
+        } else if name.starts_with("k10temp-") || name.starts_with("zenpower-") {
+            if let Some(package) = get_amd_package(chip) {
+                res.packages.insert(amd_packages, package);
+                amd_packages += 1;
+            }
+        }
+    }
+
+    if res.packages.is_empty() {
+        return Err(err!(
+            MissingSensorChip,
+            "Is the coretemp, k10temp or zenpower module loaded?",
+            "No CPU temperature sensors found"
+        ));
+    }
+
+    for package in res.packages.values_mut() {
+        if package.temp.is_none() {
+            // manually calculate average
+            let cores = package.cores.values().chain(package.dies.values());
+            package.temp = Some(average(cores.map(|core| core.temp))?);
+        }
     }
 
+    res.avg = average(res.packages.values().filter_map(|package| package.temp))?;
+
     Ok(res)
 }
 
@@ -368,87 +800,420 @@ pub fn get_core_temp_from_sensors_output(
     sensors_output: SensorsOutput,
     core_id: u8,
 ) -> Result<CoreTemperature, Error> {
-    let json_value: serde_json::Value =
-        serde_json::from_str(&sensors_output).expect("failed to parse JSON");
-
-    // get individual core temperatures
-    let coretemps = json_value
-        .get("coretemp-isa-0000")
-        .expect("failed to find key")
-        .as_object()
-        .expect("failed to find key");
-
-    let core_temp = coretemps.get(&format!("Core {core_id}"));
-    if let Some(value) = core_temp {
-        let temp = value
-            .get(format!("temp{}_input", core_id + 2))
-            .expect("core id somehow invalid?")
-            .as_f64()
-            .expect("expected a float, got something else") as u8;
-
-        let max = value
-            .get(format!("temp{}_max", core_id + 2))
-            .expect("core id somehow invalid?")
-            .as_f64()
-            .expect("expected a float, got something else") as u8;
-
-        let critical = value
-            .get(format!("temp{}_crit", core_id + 2))
-            .expect("core id somehow invalid?")
-            .as_f64()
-            .expect("expected a float, got something else") as u8;
-
-        let coretemp = CoreTemperature::new(temp, max, critical);
-        Ok(coretemp)
+    get_core_temp_of(get_temps_from_sensors_output(sensors_output)?, core_id)
+}
+
+/// Take the core `core_id` of the first package out of `temps`.
+fn get_core_temp_of(temps: Temperatures, core_id: u8) -> Result<CoreTemperature, Error> {
+    let package = temps.packages.values().next();
+
+    if let Some(coretemp) = package.and_then(|package| package.cores.get(&core_id)) {
+        Ok(coretemp.clone())
+    } else if package.is_some_and(|package| !package.dies.is_empty()) {
+        Err(err!(
+            InvalidValue,
+            "AMD CPUs only report per-die (CCD) temperatures",
+            "Core {} is not valid!",
+            core_id
+        ))
     } else {
-        Err(err!(InvalidValue, "Core {} is not valid!", core_id))
+        Err(err!(
+            InvalidValue,
+            "Run `sensors` to see the available cores",
+            "Core {} is not valid!",
+            core_id
+        ))
+    }
+}
+
+/// Get every temperature sensor of every chip, in the order of the chips and labels.
+pub fn get_sensor_readings_from_sensors_output(
+    sensors_output: SensorsOutput,
+) -> Result<Vec<SensorReading>, Error> {
+    let chips = parse_sensors_output(&sensors_output)?;
+
+    let mut res = Vec::new();
+    for (chip, features) in &chips {
+        let Some(features) = features.as_object() else {
+            continue;
+        };
+
+        // skips `Adapter` and any non-temperature features (fans, voltages, ...)
+        for (label, feature) in features {
+            let Some(temp) = get_feature_attr(feature, "input") else {
+                continue;
+            };
+
+            res.push(SensorReading {
+                chip: chip.clone(),
+                label: label.clone(),
+                temp: MilliCelsius::from_celsius(temp),
+                max: get_feature_attr(feature, "max").map(MilliCelsius::from_celsius),
+                critical: get_feature_attr(feature, "crit").map(MilliCelsius::from_celsius),
+            });
+        }
     }
+
+    if res.is_empty() {
+        return Err(err!(
+            NoReadings,
+            "Are the hwmon drivers for your hardware loaded?",
+            "No temperature sensors found"
+        ));
+    }
+
+    Ok(res)
+}
+
+pub fn get_sensor_readings() -> Result<Vec<SensorReading>, Error> {
+    get_sensor_readings_from(TempSource::default())
+}
+
+pub fn get_sensor_readings_from(source: TempSource) -> Result<Vec<SensorReading>, Error> {
+    let sensors_output = get_sensors_output_from(source)?;
+    get_sensor_readings_from_sensors_output(sensors_output)
 }
 
 pub fn get_temps() -> Result<Temperatures, Error> {
-    let sensors_output = get_sensors_output()?;
-    get_temps_from_sensors_output(sensors_output) // Propagate both the result and error
+    get_temps_from(TempSource::default())
 }
 
+pub fn get_temps_from(source: TempSource) -> Result<Temperatures, Error> {
+    let sensors_output = get_sensors_output_from(source)?;
+    let mut temps = get_temps_from_sensors_output(sensors_output)?;
+
+    topology::classify_cores(&mut temps);
+    Ok(temps)
+}
+
+/// Get the temperature of a core on the first package.
 pub fn get_core_temp(core_id: u8) -> Result<CoreTemperature, Error> {
-    let sensors_output = get_sensors_output()?;
-    get_core_temp_from_sensors_output(sensors_output, core_id)
+    get_core_temp_from(TempSource::default(), core_id)
+}
+
+pub fn get_core_temp_from(source: TempSource, core_id: u8) -> Result<CoreTemperature, Error> {
+    // through get_temps_from, so the core type is filled in too
+    get_core_temp_of(get_temps_from(source)?, core_id)
+}
+
+/// Get the IDs of the cores on the first package.
+pub fn get_cores() -> Result<Vec<u8>, Error> {
+    get_cores_from(TempSource::default())
+}
+
+pub fn get_cores_from(source: TempSource) -> Result<Vec<u8>, Error> {
+    let stdout = get_sensors_output_from(source)?;
+    let temps = get_temps_from_sensors_output(stdout)?;
+
+    // AMD CPUs have no per-core readings, so this is empty for them
+    Ok(temps
+        .packages
+        .into_values()
+        .next()
+        .map(|package| package.cores.into_keys().collect())
+        .unwrap_or_default())
+}
+
+/// An empty directory of its own for `test`, as tests run in parallel.
+#[cfg(test)]
+pub(crate) fn test_dir(test: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("tpfanspeed-{}-{test}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
-pub fn get_cores() -> Result<Vec<u8>, ErrorKind> {
-    let output = std::process::Command::new("sensors")
-        .arg("-j")
-        .output()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_0_is_only_read() {
+        assert!(FanSpeed::from_string("0".to_string()).is_err());
+        assert!(serde_json::from_str::<FanSpeed>("0").is_err());
+        assert_eq!(
+            FanSpeed::from_string("7".to_string()).unwrap(),
+            FanSpeed::Level(7)
+        );
+
+        let status = get_fan_status_from_proc_output(
+            "status:\t\tenabled\nspeed:\t\t0\nlevel:\t\t0\n".to_string(),
+        )
         .unwrap();
+        assert_eq!(status.level, FanSpeed::Level(0));
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
+        let json = serde_json::to_string(&status).unwrap();
+        let status: FanStatus = serde_json::from_str(&json).unwrap();
+        assert_eq!(status.level, FanSpeed::Level(0));
+    }
 
-    let json_value: Value = serde_json::from_str(&stdout).unwrap();
+    #[test]
+    fn pwm_boundaries() {
+        for (pwm, level) in [
+            (0, 1),
+            (31, 1),
+            (32, 1),
+            (63, 1),
+            (64, 2),
+            (223, 6),
+            (224, 7),
+            (255, 7),
+        ] {
+            assert_eq!(FanSpeed::from_pwm(pwm), FanSpeed::Level(level), "{pwm}");
+        }
 
-    let coretemps = json_value
-        .get("coretemp-isa-0000")
-        .expect("failed to find key")
-        .as_object()
-        .expect("failed to find key");
+        assert_eq!(FanSpeed::Level(0).to_pwm(), Some(0));
+        assert_eq!(FanSpeed::Level(1).to_pwm(), Some(36));
+        assert_eq!(FanSpeed::Level(7).to_pwm(), Some(255));
+        assert_eq!(FanSpeed::Level(9).to_pwm(), Some(255));
+        assert_eq!(FanSpeed::FullSpeed.to_pwm(), Some(255));
+        assert_eq!(FanSpeed::Disengaged.to_pwm(), Some(255));
+        assert_eq!(FanSpeed::Auto.to_pwm(), None);
+
+        for level in 1..=7 {
+            let pwm = FanSpeed::Level(level).to_pwm().unwrap();
+            assert_eq!(FanSpeed::from_pwm(pwm), FanSpeed::Level(level), "{pwm}");
+        }
+    }
 
-    let mut res = Vec::new();
+    #[test]
+    fn malformed_sensors_output_is_an_error() {
+        for output in ["", "not json", "[1, 2]", r#"{"coretemp-isa-0000": "#] {
+            let e = get_sensor_readings_from_sensors_output(output.to_string()).unwrap_err();
+            assert_eq!(e.kind(), &ErrorKind::MalformedSensorJson, "{output:?}");
+            let e = get_temps_from_sensors_output(output.to_string()).unwrap_err();
+            assert_eq!(e.kind(), &ErrorKind::MalformedSensorJson, "{output:?}");
+        }
 
-    for key in coretemps.keys() {
-        let k = key.as_str();
+        // chips and features that aren't objects are skipped, not trusted
+        let readings = get_sensor_readings_from_sensors_output(
+            r#"{
+                "bogus-isa-0000": 42,
+                "acpitz-acpi-0": {
+                    "Adapter": "ACPI interface",
+                    "temp1": {"temp1_input": 45.0, "temp1_crit": 128.0},
+                    "temp2": "hot"
+                }
+            }"#
+            .to_string(),
+        )
+        .unwrap();
+        assert_eq!(readings.len(), 1);
+        assert_eq!(readings[0].temp, MilliCelsius(45_000));
+        assert_eq!(readings[0].critical, Some(MilliCelsius(128_000)));
+    }
 
-        if !k.contains("Core ") {
-            continue;
+    #[test]
+    fn amd_tctl_only() {
+        let temps = get_temps_from_sensors_output(
+            r#"{"k10temp-pci-00c3": {"Adapter": "PCI adapter", "Tctl": {"temp1_input": 61.5}}}"#
+                .to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(temps.avg, MilliCelsius(61_500));
+        let package = &temps.packages[&0];
+        assert_eq!(package.temp, Some(MilliCelsius(61_500)));
+        assert!(package.cores.is_empty());
+        assert!(package.dies.is_empty());
+    }
+
+    #[test]
+    fn amd_dies() {
+        let output = r#"{
+            "k10temp-pci-00c3": {
+                "Adapter": "PCI adapter",
+                "Tctl": {"temp1_input": 70.0},
+                "Tccd1": {"temp3_input": 60.0},
+                "Tccd2": {"temp4_input": 64.0, "temp4_max": 90.0, "temp4_crit": 100.0}
+            }
+        }"#;
+        let temps = get_temps_from_sensors_output(output.to_string()).unwrap();
+
+        // Tctl, not the average of the dies
+        assert_eq!(temps.avg, MilliCelsius(70_000));
+        let dies = &temps.packages[&0].dies;
+        assert_eq!(dies.keys().copied().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(dies[&1].label, "Tccd1");
+        assert_eq!(dies[&1].temp, MilliCelsius(60_000));
+        assert_eq!(dies[&1].max, AMD_DEFAULT_MAX);
+        assert_eq!(dies[&1].critical, AMD_DEFAULT_CRIT);
+        assert_eq!(dies[&2].max, MilliCelsius(90_000));
+        assert_eq!(dies[&2].critical, MilliCelsius(100_000));
+
+        // zenpower without Tctl: the average of the dies
+        let output = output
+            .replace("k10temp", "zenpower")
+            .replace(r#""Tctl": {"temp1_input": 70.0},"#, "");
+        let temps = get_temps_from_sensors_output(output).unwrap();
+        assert_eq!(temps.packages[&0].temp, Some(MilliCelsius(62_000)));
+
+        let e = get_core_temp_of(temps, 0).unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::InvalidValue);
+        assert_eq!(
+            e.help(),
+            Some("AMD CPUs only report per-die (CCD) temperatures")
+        );
+    }
+
+    #[test]
+    fn intel_sockets() {
+        let socket = |package: &str, temp: f64| {
+            format!(
+                r#"{{
+                    "Adapter": "ISA adapter",
+                    {package}
+                    "Core 0": {{"temp2_input": {temp}, "temp2_max": 100.0, "temp2_crit": 110.0}},
+                    "Core 1": {{"temp3_input": {}}}
+                }}"#,
+                temp + 2.0
+            )
+        };
+        let output = format!(
+            r#"{{"coretemp-isa-0000": {}, "coretemp-isa-0001": {}}}"#,
+            socket(r#""Package id 0": {"temp1_input": 50.0},"#, 48.0),
+            socket(r#""Package id 1": {"temp1_input": 60.0},"#, 58.0),
+        );
+        let temps = get_temps_from_sensors_output(output).unwrap();
+
+        assert_eq!(temps.packages.len(), 2);
+        assert_eq!(temps.packages[&0].temp, Some(MilliCelsius(50_000)));
+        assert_eq!(temps.packages[&1].temp, Some(MilliCelsius(60_000)));
+        assert_eq!(temps.packages[&1].cores[&0].temp, MilliCelsius(58_000));
+        assert_eq!(temps.packages[&1].cores[&0].critical, MilliCelsius(110_000));
+        assert_eq!(temps.packages[&1].cores[&1].max, INTEL_DEFAULT_TJMAX);
+        assert_eq!(temps.avg, MilliCelsius(55_000));
+
+        // older CPUs without `Package id N`: keyed by the ISA address, averaged over their cores
+        let output = format!(
+            r#"{{"coretemp-isa-0000": {}, "coretemp-isa-0001": {}}}"#,
+            socket("", 48.0),
+            socket("", 58.0),
+        );
+        let temps = get_temps_from_sensors_output(output).unwrap();
+
+        assert_eq!(temps.packages.keys().copied().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(temps.packages[&0].temp, Some(MilliCelsius(49_000)));
+        assert_eq!(temps.packages[&1].temp, Some(MilliCelsius(59_000)));
+        assert_eq!(temps.packages[&1].cores.len(), 2);
+    }
+
+    #[test]
+    fn averages() {
+        let e = average(std::iter::empty()).unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::NoReadings);
+
+        assert_eq!(
+            average([50_000, 55_000, 61_000].map(MilliCelsius).into_iter()).unwrap(),
+            MilliCelsius(55_333)
+        );
+        // wouldn't fit in the i32 of a MilliCelsius
+        assert_eq!(
+            average(std::iter::repeat_n(MilliCelsius(i32::MAX), 4)).unwrap(),
+            MilliCelsius(i32::MAX)
+        );
+
+        // a chip with nothing to average is no package at all
+        let e = get_temps_from_sensors_output(
+            r#"{"coretemp-isa-0000": {"Adapter": "ISA adapter"}}"#.to_string(),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::MissingSensorChip);
+
+        // across packages, not cores: one hot socket with few cores counts as much as a cool one
+        let output = r#"{
+            "coretemp-isa-0000": {
+                "Core 0": {"temp2_input": 40.0},
+                "Core 1": {"temp3_input": 40.0},
+                "Core 2": {"temp4_input": 40.0}
+            },
+            "coretemp-isa-0001": {
+                "Core 0": {"temp2_input": 80.0}
+            }
+        }"#;
+        let temps = get_temps_from_sensors_output(output.to_string()).unwrap();
+        assert_eq!(temps.avg, MilliCelsius(60_000));
+    }
+
+    #[test]
+    fn malformed_procfs_keeps_the_cause() {
+        use std::error::Error as _;
+
+        let e = get_fan_status_from_proc_output(
+            "status:\t\tenabled\nspeed:\t\tfast\nlevel:\t\tauto\n".to_string(),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::MalformedProcfs);
+        let source = e.source().unwrap();
+        assert!(source.downcast_ref::<std::num::ParseIntError>().is_some());
+
+        let e = get_fan_status_from_proc_output(
+            "status:\t\tenabled\nspeed:\t\t2900\nlevel:\t\t300\n".to_string(),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::MalformedProcfs);
+        let source = e.source().unwrap().downcast_ref::<Error>().unwrap();
+        assert_eq!(source.kind(), &ErrorKind::ValueTooHigh);
+    }
+
+    /// `/proc/acpi/ibm/fan` with fan_control=1.
+    const PROC_FAN: &str = "status:\t\tenabled
+speed:\t\t2900
+level:\t\tauto
+commands:\tlevel <level> (<level> is 0-7, auto, disengaged, full-speed)
+commands:\tenable, disable
+commands:\twatchdog <timeout> (<timeout> is 0 (off), 1-120 (seconds))
+";
+
+    #[test]
+    fn proc_fan() {
+        let status = get_fan_status_from_proc_output(PROC_FAN.to_string()).unwrap();
+
+        assert!(status.enabled);
+        assert_eq!(status.speed, 2900);
+        assert_eq!(status.level, FanSpeed::Auto);
+        assert_eq!(status.commands.len(), 3);
+        for command in ["level", "enable", "disable", "watchdog"] {
+            assert!(status.supports(command), "{command}");
         }
+        assert!(!status.supports("0-7"));
+        // the watchdog is read from thinkpad_hwmon, not from here
+        assert_eq!(status.watchdog, None);
 
-        let coreid = k
-            .split_once(' ')
-            .expect("expected Core and an ID")
-            .1
-            .parse::<u8>()
-            .expect("ID should be a valid number");
+        // without fan_control=1 there are no commands
+        let status = get_fan_status_from_proc_output(
+            "status:\t\tdisabled\nspeed:\t\t0\nlevel:\t\tfull-speed\n".to_string(),
+        )
+        .unwrap();
+        assert!(!status.enabled);
+        assert_eq!(status.level, FanSpeed::FullSpeed);
+        assert!(!status.is_controllable());
+    }
 
-        res.push(coreid)
+    #[test]
+    fn proc_fan_missing_keys() {
+        for key in ["status", "speed", "level"] {
+            let output: String = PROC_FAN
+                .lines()
+                .filter(|line| !line.starts_with(key))
+                .map(|line| format!("{line}\n"))
+                .collect();
+
+            let e = get_fan_status_from_proc_output(output).unwrap_err();
+            assert_eq!(e.kind(), &ErrorKind::MalformedProcfs, "{key}");
+            assert!(e.desc().unwrap().contains(&format!("`{key}`")), "{e}");
+        }
+
+        let e = get_fan_status_from_proc_output("no colon here\n".to_string()).unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::MalformedProcfs);
     }
 
-    Ok(res)
+    #[test]
+    fn proc_fan_unknown_keys() {
+        let output = format!("{PROC_FAN}\nfan2 speed:\t3000\nwhatever:\n");
+        let status = get_fan_status_from_proc_output(output).unwrap();
+
+        assert_eq!(status.speed, 2900);
+        assert_eq!(status.commands.len(), 3);
+    }
 }
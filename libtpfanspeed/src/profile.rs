@@ -0,0 +1,172 @@
+//! Named fan settings like `quiet` or `performance`, each a fixed level or a curve.
+//!
+//! Profiles are defined in the `[profiles]` table of the [`crate::config::Config`],
+//! on top of the [`builtin`] ones. The one picked last is kept in a state file,
+//! [`STATE_PATH`] unless the config says otherwise, for tpfand (or `tpfanctl
+//! profile restore`) to set again after a reboot, until a level is set by hand.
+
+use crate::{curve::FanCurve, error::*, FanSpeed};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+pub const STATE_PATH: &str = "/var/lib/tpfanspeed/profile";
+
+/// In config files, either `quiet = { level = 2 }` or a curve:
+///
+/// ```toml
+/// [profiles.gaming.curve]
+/// base = 3
+/// points = [
+///     { speed = 7, rising = 70, falling = 65 },
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Level(FanSpeed),
+    /// Only tpfand can follow a curve.
+    Curve(FanCurve),
+}
+
+/// `quiet` (level 2), `balanced` (`auto`) and `performance` (level 7).
+/// With a config, `balanced` is its `[curve]`, see [`crate::config::Config::all_profiles`].
+pub fn builtin() -> BTreeMap<String, Profile> {
+    [
+        ("quiet", Profile::Level(FanSpeed::Level(2))),
+        ("balanced", Profile::Level(FanSpeed::Auto)),
+        ("performance", Profile::Level(FanSpeed::Level(7))),
+    ]
+    .into_iter()
+    .map(|(name, profile)| (name.to_string(), profile))
+    .collect()
+}
+
+/// Take the profile `name` out of `profiles`.
+pub fn lookup(mut profiles: BTreeMap<String, Profile>, name: &str) -> Result<Profile, Error> {
+    profiles.remove(name).ok_or_else(|| {
+        err!(
+            InvalidValue,
+            format!(
+                "Valid profiles are {}",
+                profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            ),
+            "There is no {} profile",
+            name
+        )
+    })
+}
+
+/// The profile saved in the state file at `path`, if any.
+pub fn load_active(path: impl AsRef<Path>) -> Result<Option<String>, Error> {
+    let path = path.as_ref();
+
+    match fs::read_to_string(path) {
+        Ok(name) => Ok(Some(name.trim().to_string()).filter(|name| !name.is_empty())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(state_err(e, "read from", path)),
+    }
+}
+
+/// Save `name` to the state file at `path`, creating its directory if needed.
+pub fn save_active(path: impl AsRef<Path>, name: &str) -> Result<(), Error> {
+    let path = path.as_ref();
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| state_err(e, "create the directory of", path))?;
+    }
+
+    fs::write(path, format!("{name}\n")).map_err(|e| state_err(e, "write to", path))
+}
+
+/// Forget the saved profile, once the fan is set by other means.
+pub fn clear_active(path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(state_err(e, "remove", path)),
+        _ => Ok(()),
+    }
+}
+
+fn state_err(e: io::Error, action: &str, path: &Path) -> Error {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => err!(
+            PermissionDenied,
+            "Do you have sufficient permissions?",
+            "while trying to {} {}",
+            action,
+            path.display()
+        )
+        .with_source(e),
+        _ => generic_err!(e),
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Level(level) => write!(f, "level {level}"),
+            Self::Curve(curve) => {
+                write!(f, "curve from {}", curve.base())?;
+                for point in curve.points() {
+                    write!(f, ", {} at {}°C", point.speed, point.rising)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_profiles() {
+        let profiles = builtin();
+
+        assert_eq!(
+            profiles.keys().collect::<Vec<_>>(),
+            ["balanced", "performance", "quiet"]
+        );
+        assert_eq!(profiles["quiet"], Profile::Level(FanSpeed::Level(2)));
+        assert_eq!(profiles["balanced"], Profile::Level(FanSpeed::Auto));
+        assert_eq!(profiles["performance"], Profile::Level(FanSpeed::Level(7)));
+    }
+
+    #[test]
+    fn lookup_takes_the_profile_or_lists_the_others() {
+        assert_eq!(
+            lookup(builtin(), "quiet").unwrap(),
+            Profile::Level(FanSpeed::Level(2))
+        );
+
+        let e = lookup(builtin(), "loud").unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::InvalidValue);
+        assert_eq!(
+            e.help(),
+            Some("Valid profiles are balanced, performance, quiet")
+        );
+    }
+
+    #[test]
+    fn active_profile_state() {
+        let path = crate::test_dir("profile-state").join("state/profile");
+
+        assert_eq!(load_active(&path).unwrap(), None);
+        // nothing to forget is fine
+        clear_active(&path).unwrap();
+
+        save_active(&path, "quiet").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "quiet\n");
+        assert_eq!(load_active(&path).unwrap(), Some("quiet".to_string()));
+
+        fs::write(&path, "  \n").unwrap();
+        assert_eq!(load_active(&path).unwrap(), None);
+
+        save_active(&path, "performance").unwrap();
+        clear_active(&path).unwrap();
+        assert!(!path.exists());
+        assert_eq!(load_active(&path).unwrap(), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! CPU topology from sysfs, used to tell P-cores from E-cores on hybrid CPUs.
+
+use crate::{CoreType, Temperatures};
+use std::{collections::BTreeMap, fs, path::Path};
+
+pub const SYS_DEVICES_PATH: &str = "/sys/devices";
+
+/// Core types keyed by `(package id, core id)`, as used by coretemp's
+/// `Core N` labels. Empty on non-hybrid CPUs or if sysfs isn't readable.
+pub fn get_core_types() -> BTreeMap<(u8, u8), CoreType> {
+    get_core_types_from(SYS_DEVICES_PATH)
+}
+
+/// Like [`get_core_types`], with `devices` in place of `/sys/devices`.
+pub fn get_core_types_from(devices: impl AsRef<Path>) -> BTreeMap<(u8, u8), CoreType> {
+    let devices = devices.as_ref();
+    let mut cpus = Vec::new();
+
+    // the hybrid PMUs registered by the kernel on Alder Lake and newer
+    for (pmu, core_type) in [
+        ("cpu_core", CoreType::Performance),
+        ("cpu_atom", CoreType::Efficiency),
+    ] {
+        if let Some(list) = read_trimmed(&devices.join(pmu).join("cpus")) {
+            cpus.extend(parse_cpulist(&list).into_iter().map(|cpu| (cpu, core_type)));
+        }
+    }
+
+    // otherwise, try system/cpu/types/<vendor>_<type>_<id>/cpulist
+    if cpus.is_empty() {
+        if let Ok(entries) = fs::read_dir(devices.join("system/cpu/types")) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let core_type = if name.contains("atom") {
+                    CoreType::Efficiency
+                } else if name.contains("core") {
+                    CoreType::Performance
+                } else {
+                    continue;
+                };
+
+                if let Some(list) = read_trimmed(&entry.path().join("cpulist")) {
+                    cpus.extend(parse_cpulist(&list).into_iter().map(|cpu| (cpu, core_type)));
+                }
+            }
+        }
+    }
+
+    let mut res = BTreeMap::new();
+    for (cpu, core_type) in cpus {
+        let topology = devices.join(format!("system/cpu/cpu{cpu}/topology"));
+        let id =
+            |file: &str| read_trimmed(&topology.join(file)).and_then(|id| id.parse::<u8>().ok());
+
+        if let (Some(package), Some(core)) = (id("physical_package_id"), id("core_id")) {
+            res.insert((package, core), core_type);
+        }
+    }
+
+    res
+}
+
+/// Fill in the [`CoreType`] of every core in `temps`.
+pub fn classify_cores(temps: &mut Temperatures) {
+    classify_cores_from(SYS_DEVICES_PATH, temps)
+}
+
+/// Like [`classify_cores`], with `devices` in place of `/sys/devices`.
+pub fn classify_cores_from(devices: impl AsRef<Path>, temps: &mut Temperatures) {
+    let core_types = get_core_types_from(devices);
+    if core_types.is_empty() {
+        return;
+    }
+
+    for (package_id, package) in temps.packages.iter_mut() {
+        for (core_id, core) in package.cores.iter_mut() {
+            if let Some(core_type) = core_types.get(&(*package_id, *core_id)) {
+                core.core_type = *core_type;
+            }
+        }
+    }
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+/// Parse a kernel CPU list like `0-3,8,10-11`.
+fn parse_cpulist(list: &str) -> Vec<u32> {
+    list.split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((start, end)) => Some(start.trim().parse().ok()?..=end.trim().parse().ok()?),
+            None => {
+                let cpu = range.trim().parse().ok()?;
+                Some(cpu..=cpu)
+            }
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CoreTemperature, MilliCelsius, PackageTemperatures};
+
+    #[test]
+    fn cpulists() {
+        assert_eq!(parse_cpulist("0-3,8,10-11"), [0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cpulist("5"), [5]);
+        assert_eq!(parse_cpulist(""), Vec::<u32>::new());
+        // whatever can't be parsed is skipped
+        assert_eq!(parse_cpulist("0-1,x,3-,-4,6"), [0, 1, 6]);
+        assert_eq!(parse_cpulist("3-1"), Vec::<u32>::new());
+    }
+
+    /// Two P-cores with two threads each (CPUs 0-3) and two E-cores (CPUs 4-5).
+    fn devices(test: &str) -> std::path::PathBuf {
+        let devices = crate::test_dir(&format!("topology-{test}"));
+
+        for (cpu, core) in [(0, 0), (1, 0), (2, 4), (3, 4), (4, 8), (5, 9)] {
+            let topology = devices.join(format!("system/cpu/cpu{cpu}/topology"));
+            fs::create_dir_all(&topology).unwrap();
+            fs::write(topology.join("physical_package_id"), "0\n").unwrap();
+            fs::write(topology.join("core_id"), format!("{core}\n")).unwrap();
+        }
+
+        devices
+    }
+
+    fn write_cpulist(dir: &Path, file: &str, list: &str) {
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join(file), format!("{list}\n")).unwrap();
+    }
+
+    fn hybrid() -> BTreeMap<(u8, u8), CoreType> {
+        BTreeMap::from([
+            ((0, 0), CoreType::Performance),
+            ((0, 4), CoreType::Performance),
+            ((0, 8), CoreType::Efficiency),
+            ((0, 9), CoreType::Efficiency),
+        ])
+    }
+
+    #[test]
+    fn hybrid_pmus() {
+        let devices = devices("pmus");
+        write_cpulist(&devices.join("cpu_core"), "cpus", "0-3");
+        write_cpulist(&devices.join("cpu_atom"), "cpus", "4-5");
+
+        assert_eq!(get_core_types_from(&devices), hybrid());
+        let _ = fs::remove_dir_all(devices);
+    }
+
+    #[test]
+    fn cpu_types() {
+        let devices = devices("types");
+        let types = devices.join("system/cpu/types");
+        write_cpulist(&types.join("intel_core_1"), "cpulist", "0-3");
+        write_cpulist(&types.join("intel_atom_1"), "cpulist", "4,5");
+
+        assert_eq!(get_core_types_from(&devices), hybrid());
+        let _ = fs::remove_dir_all(devices);
+    }
+
+    #[test]
+    fn neither_is_unknown() {
+        let devices = devices("neither");
+        assert!(get_core_types_from(&devices).is_empty());
+
+        let core = CoreTemperature::new(
+            MilliCelsius(50_000),
+            MilliCelsius(100_000),
+            MilliCelsius(100_000),
+        );
+        let mut temps = Temperatures::new();
+        temps.packages.insert(
+            0,
+            PackageTemperatures {
+                cores: BTreeMap::from([(0, core.clone()), (8, core)]),
+                ..Default::default()
+            },
+        );
+
+        classify_cores_from(&devices, &mut temps);
+        assert!(temps.packages[&0]
+            .cores
+            .values()
+            .all(|core| core.core_type == CoreType::Unknown));
+
+        // and with the PMUs, the types are filled in
+        write_cpulist(&devices.join("cpu_core"), "cpus", "0-3");
+        write_cpulist(&devices.join("cpu_atom"), "cpus", "4-5");
+        classify_cores_from(&devices, &mut temps);
+        let cores = &temps.packages[&0].cores;
+        assert_eq!(cores[&0].core_type, CoreType::Performance);
+        assert_eq!(cores[&8].core_type, CoreType::Efficiency);
+        let _ = fs::remove_dir_all(devices);
+    }
+}
@@ -0,0 +1,364 @@
+//! `tpfanctl bar`, a status bar module that keeps running and prints a line
+//! every interval, in the protocol of the bar.
+//!
+//! With i3bar (and swaybar), clicks come back on stdin: left click or scroll up
+//! goes to the next level, right click or scroll down to the previous one, and
+//! middle click hands the fan back to tpfand (or auto). Waybar and polybar run
+//! a command on click instead, see `tpfanctl fan cycle`.
+
+use crate::{output::Status, template::Template, Application};
+use color_eyre::owo_colors::AnsiColors;
+use libtpfanspeed as libtpfs;
+use libtpfs::error::*;
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_TEMPLATE: &str = "{avg:0}°C {rpm}rpm L{level}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarProtocol {
+    /// One JSON object per line, for a `custom` module with `"return-type": "json"`.
+    Waybar,
+    /// The i3bar JSON protocol, also spoken by swaybar, with click events.
+    I3bar,
+    /// Plain lines with `%{F}` colors and `%{A}` click actions, for a `custom/script` with `tail = true`.
+    Polybar,
+}
+
+impl BarProtocol {
+    pub fn from_string(value: String) -> Result<Self, Error> {
+        match value.as_ref() {
+            "waybar" => Ok(Self::Waybar),
+            "i3bar" | "swaybar" => Ok(Self::I3bar),
+            "polybar" => Ok(Self::Polybar),
+            _ => Err(err!(
+                InvalidValue,
+                "Valid protocols are waybar, i3bar and polybar",
+                "{} is an invalid bar protocol",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for BarProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Waybar => "waybar",
+            Self::I3bar => "i3bar",
+            Self::Polybar => "polybar",
+        };
+
+        write!(f, "{s}")
+    }
+}
+
+/// What the user clicked on, in i3bar's button numbers.
+#[derive(Debug, PartialEq, Eq)]
+enum Click {
+    Next,
+    Previous,
+    Release,
+}
+
+impl Click {
+    fn from_button(button: u64) -> Option<Self> {
+        match button {
+            1 | 4 => Some(Self::Next),
+            3 | 5 => Some(Self::Previous),
+            2 => Some(Self::Release),
+            _ => None,
+        }
+    }
+}
+
+/// The CSS class and color for `temp`, from the dashboard's thresholds.
+fn temp_class(temp: libtpfs::MilliCelsius) -> (&'static str, &'static str) {
+    match Application::get_temp_color(temp) {
+        AnsiColors::Blue => ("cold", "#81a1c1"),
+        AnsiColors::Green => ("normal", "#a3be8c"),
+        AnsiColors::Yellow => ("warm", "#ebcb8b"),
+        _ => ("hot", "#bf616a"),
+    }
+}
+
+fn tooltip(status: &Status) -> String {
+    let mut out = format!("Average: {}°C", status.temps.avg);
+
+    for (package, core, temp) in status.temps.cores() {
+        let _ = write!(out, "\nPackage {package} core {core}: {}°C", temp.temp);
+    }
+    for (package_id, package) in &status.temps.packages {
+        for (die, temp) in &package.dies {
+            let _ = write!(out, "\nPackage {package_id} CCD {die}: {}°C", temp.temp);
+        }
+    }
+    let _ = write!(out, "\nLevel: {}", status.fan.level);
+    for fan in &status.fans {
+        let _ = write!(out, "\nFan {}: {} RPM", fan.id, fan.rpm);
+    }
+    if let Some(control) = &status.control {
+        let _ = write!(out, "\nControlled by tpfand: {control}");
+    }
+
+    out
+}
+
+/// One line of the bar, or the error in place of the readings. Bars don't
+/// understand ANSI colors, the line is colored by the bar's own means instead.
+fn render(protocol: BarProtocol, template: &Template, status: Result<Status, Error>) -> String {
+    let (text, tooltip, class, color) =
+        match status.and_then(|s| Ok((template.render_plain(&s)?, s))) {
+            Ok((text, status)) => {
+                let (class, color) = temp_class(status.temps.avg);
+                (text, tooltip(&status), class, color)
+            }
+            Err(e) => (e.kind().to_string(), e.to_string(), "error", "#bf616a"),
+        };
+
+    match protocol {
+        BarProtocol::Waybar => serde_json::json!({
+            "text": text,
+            "tooltip": tooltip,
+            "class": class,
+        })
+        .to_string(),
+        BarProtocol::I3bar => {
+            let block = serde_json::json!({
+                "name": "tpfanctl",
+                "full_text": text,
+                "color": color,
+                "urgent": class == "hot" || class == "error",
+            });
+            format!("[{block}],")
+        }
+        BarProtocol::Polybar => {
+            let exe = std::env::current_exe()
+                .map(|p| p.display().to_string())
+                .unwrap_or_else(|_| "tpfanctl".to_string());
+            polybar_line(&exe, color, &text)
+        }
+    }
+}
+
+/// `text` in `color`, cycling the level with `exe` on left and right click.
+fn polybar_line(exe: &str, color: &str, text: &str) -> String {
+    // polybar ends an action at the first unescaped colon
+    let exe = exe.replace(':', "\\:");
+    format!(
+        "%{{A1:{exe} fan cycle:}}%{{A3:{exe} fan cycle --reverse:}}%{{F{color}}}{text}%{{F-}}%{{A}}%{{A}}"
+    )
+}
+
+/// Read i3bar click events from stdin until it closes. They come as an
+/// endless JSON array, one object per line: `[`, `{...}`, `,{...}`, ...
+fn read_clicks(clicks: mpsc::Sender<Click>) {
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else { return };
+        let line = line.trim().trim_start_matches(['[', ',']);
+
+        let Ok(event) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        let button = event.get("button").and_then(|b| b.as_u64());
+
+        if let Some(click) = button.and_then(Click::from_button) {
+            if clicks.send(click).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Application {
+    /// `tpfanctl bar`. Runs until the bar goes away.
+    pub fn bar(&self, protocol: BarProtocol, interval: Duration, template: &str) {
+        let template = Template::parse(template).unwrap_or_else(|e| crate::err(e));
+        let (tx, clicks) = mpsc::channel();
+
+        let mut stdout = io::stdout().lock();
+        if protocol == BarProtocol::I3bar {
+            let header = serde_json::json!({ "version": 1, "click_events": true });
+            if writeln!(stdout, "{header}\n[").is_err() {
+                return;
+            }
+            // keep `tx` around, so `clicks` doesn't disconnect when stdin closes
+            let tx = tx.clone();
+            thread::spawn(move || read_clicks(tx));
+        }
+
+        loop {
+            let line = render(protocol, &template, self.read_status());
+            if writeln!(stdout, "{line}").is_err() {
+                return;
+            }
+
+            let deadline = Instant::now() + interval;
+            while let Ok(click) =
+                clicks.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                let res = match click {
+                    Click::Next => self.cycle_level(false).map(|_| ()),
+                    Click::Previous => self.cycle_level(true).map(|_| ()),
+                    Click::Release => self.release(),
+                };
+
+                // no stderr with a bar, show it instead of the readings until the next update
+                if let Err(e) = res {
+                    let _ = writeln!(stdout, "{}", render(protocol, &template, Err(e)));
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtpfs::{FanReading, FanSpeed, FanStatus};
+    use serde_json::{json, Value};
+
+    /// 61.4°C on average, so `warm`.
+    fn status() -> Status {
+        let sensors = r#"{
+            "coretemp-isa-0000": {
+                "Package id 0": { "temp1_input": 61.4 },
+                "Core 0": { "temp2_input": 55.0 },
+                "Core 1": { "temp3_input": 70.0 }
+            }
+        }"#;
+
+        Status {
+            temps: libtpfs::get_temps_from_sensors_output(sensors.to_string()).unwrap(),
+            fan: FanStatus {
+                enabled: true,
+                speed: 2900,
+                level: FanSpeed::Level(3),
+                watchdog: Some(0),
+                commands: Vec::new(),
+            },
+            fans: vec![FanReading { id: 1, rpm: 2900 }],
+            control: None,
+        }
+    }
+
+    fn render_ok(protocol: BarProtocol) -> String {
+        render(
+            protocol,
+            &Template::parse(DEFAULT_TEMPLATE).unwrap(),
+            Ok(status()),
+        )
+    }
+
+    fn render_err(protocol: BarProtocol) -> String {
+        let e = err!(FileNotFound, "Did you load thinkpad_acpi?", "No fan here");
+        render(
+            protocol,
+            &Template::parse(DEFAULT_TEMPLATE).unwrap(),
+            Err(e),
+        )
+    }
+
+    #[test]
+    fn waybar() {
+        let line: Value = serde_json::from_str(&render_ok(BarProtocol::Waybar)).unwrap();
+        assert_eq!(line["text"], "61°C 2900rpm L3");
+        assert_eq!(line["class"], "warm");
+        let tooltip = line["tooltip"].as_str().unwrap();
+        assert!(tooltip.starts_with("Average: 61.4°C\n"), "{tooltip}");
+        assert!(tooltip.contains("\nPackage 0 core 1: 70.0°C"), "{tooltip}");
+        assert!(
+            tooltip.ends_with("\nLevel: 3\nFan 1: 2900 RPM"),
+            "{tooltip}"
+        );
+
+        let line: Value = serde_json::from_str(&render_err(BarProtocol::Waybar)).unwrap();
+        assert_eq!(
+            line,
+            json!({
+                "text": ErrorKind::FileNotFound.to_string(),
+                "tooltip": format!("{}: No fan here", ErrorKind::FileNotFound),
+                "class": "error",
+            })
+        );
+    }
+
+    #[test]
+    fn i3bar() {
+        // a block of the endless array, trailing comma included
+        let line = render_ok(BarProtocol::I3bar);
+        let block = line.strip_suffix(',').unwrap();
+        let block: Value = serde_json::from_str(block).unwrap();
+        assert_eq!(
+            block,
+            json!([{
+                "name": "tpfanctl",
+                "full_text": "61°C 2900rpm L3",
+                "color": "#ebcb8b",
+                "urgent": false,
+            }])
+        );
+
+        let line = render_err(BarProtocol::I3bar);
+        let block: Value = serde_json::from_str(line.strip_suffix(',').unwrap()).unwrap();
+        assert_eq!(block[0]["full_text"], ErrorKind::FileNotFound.to_string());
+        assert_eq!(block[0]["color"], "#bf616a");
+        assert_eq!(block[0]["urgent"], true);
+    }
+
+    #[test]
+    fn polybar() {
+        assert_eq!(
+            polybar_line("/opt/tpfan:ctl/tpfanctl", "#ebcb8b", "61°C"),
+            r"%{A1:/opt/tpfan\:ctl/tpfanctl fan cycle:}%{A3:/opt/tpfan\:ctl/tpfanctl fan cycle --reverse:}%{F#ebcb8b}61°C%{F-}%{A}%{A}"
+        );
+
+        let line = render_ok(BarProtocol::Polybar);
+        assert!(
+            line.ends_with("%{F#ebcb8b}61°C 2900rpm L3%{F-}%{A}%{A}"),
+            "{line}"
+        );
+        let line = render_err(BarProtocol::Polybar);
+        assert!(
+            line.contains(&format!("%{{F#bf616a}}{}%{{F-}}", ErrorKind::FileNotFound)),
+            "{line}"
+        );
+    }
+
+    #[test]
+    fn clicks() {
+        assert_eq!(Click::from_button(1), Some(Click::Next));
+        assert_eq!(Click::from_button(4), Some(Click::Next));
+        assert_eq!(Click::from_button(3), Some(Click::Previous));
+        assert_eq!(Click::from_button(5), Some(Click::Previous));
+        assert_eq!(Click::from_button(2), Some(Click::Release));
+        assert_eq!(Click::from_button(0), None);
+        assert_eq!(Click::from_button(8), None);
+    }
+
+    #[test]
+    fn protocols() {
+        for (name, protocol) in [
+            ("waybar", BarProtocol::Waybar),
+            ("i3bar", BarProtocol::I3bar),
+            ("swaybar", BarProtocol::I3bar),
+            ("polybar", BarProtocol::Polybar),
+        ] {
+            assert_eq!(
+                BarProtocol::from_string(name.to_string()).unwrap(),
+                protocol
+            );
+        }
+        assert_eq!(BarProtocol::I3bar.to_string(), "i3bar");
+
+        let e = BarProtocol::from_string("lemonbar".to_string()).unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::InvalidValue);
+    }
+}
@@ -1,12 +1,14 @@
 use clap::Parser;
-use libtpfanspeed::FanSpeed;
 use tpfanctl::*;
 
 #[derive(Parser)]
 #[command(author, long_about = None, about = "A condensed version of the tpfanctl utility, that only sets the fan speed.")]
 struct Args {
-    #[arg(help = "The fan speed in question")]
+    #[arg(help = "The fan speed in question, or a profile (see `tpfanctl profile list`)")]
     fanspeed: String,
+
+    #[arg(long, help = "Print errors as JSON, see `tpfanctl --json-errors`")]
+    json_errors: bool,
 }
 
 fn main() {
@@ -20,7 +22,7 @@ fn main() {
 
     PRINT_ERRORS.set(true).unwrap();
     PRETTY_PRINT.set(true).unwrap();
+    JSON_ERRORS.set(args.json_errors).unwrap();
 
-    let fs = FanSpeed::from_string(args.fanspeed).unwrap_or_else(|e| err(e));
-    Application::new().set_fan(fs);
+    Application::new().set_fan_or_profile(args.fanspeed);
 }
@@ -1,21 +1,139 @@
 use clap::{Parser, Subcommand};
 use libtpfanspeed as libtpfs;
-use tpfanctl::*;
+use tpfanctl::{bar::BarProtocol, output::OutputFormat, template, timed, *};
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
     #[command(about = "Print the dashboard")]
     Dash,
     #[command(about = "Print the CPU temperatures")]
-    Temp,
+    Temp {
+        #[arg(
+            short,
+            long,
+            help = "print every temperature sensor, not just the CPU's"
+        )]
+        all: bool,
+    },
     #[command(about = "Print the fan's RPM")]
     Rpm,
-    #[command(about = "Print/Modify the fan's speed setting")]
-    Fan { fanspeed: Option<String> },
+    #[command(
+        about = "Print/Modify the fan's speed setting",
+        args_conflicts_with_subcommands = true
+    )]
+    Fan {
+        #[command(subcommand)]
+        action: Option<FanCommand>,
+        fanspeed: Option<String>,
+        #[arg(
+            short,
+            long,
+            requires = "fanspeed",
+            help = "only set this fan (numbered from 1)"
+        )]
+        fan: Option<u8>,
+        #[arg(
+            long,
+            conflicts_with_all = ["fanspeed", "fan"],
+            help = "set a PWM duty cycle (0-255) instead; the EC maps it to level pwm/32, at least 1"
+        )]
+        pwm: Option<u8>,
+        #[arg(
+            long = "for",
+            value_name = "DURATION",
+            requires = "fanspeed",
+            conflicts_with = "fan",
+            value_parser = |s: &str| timed::parse_duration(s),
+            help = "go back to the previous level after this long, e.g. 90s, 10m or 1h30m"
+        )]
+        duration: Option<std::time::Duration>,
+    },
+    #[command(about = "Print the temperature, fan speed and RPM at once")]
+    Status {
+        #[arg(
+            short,
+            long,
+            help = "print a line like '{avg}°C {rpm}rpm L{level}' instead (for status bars)",
+            long_help = format!(
+                "print a line like '{{avg}}°C {{rpm}}rpm L{{level}}' instead (for status bars). Placeholders:\n\n{}",
+                template::PLACEHOLDERS
+            )
+        )]
+        template: Option<String>,
+    },
+    #[command(about = "Keep printing the status for waybar, i3bar/swaybar or polybar")]
+    Bar {
+        #[arg(
+            short,
+            long,
+            value_parser = |s: &str| BarProtocol::from_string(s.to_string()),
+            help = "the bar's protocol (waybar, i3bar, polybar)"
+        )]
+        protocol: BarProtocol,
+        #[arg(short, long, default_value_t = 2, help = "seconds between updates")]
+        interval: u64,
+        #[arg(
+            short,
+            long,
+            default_value = bar::DEFAULT_TEMPLATE,
+            help = "what to print, see `tpfanctl status --help`"
+        )]
+        template: String,
+    },
+    #[command(about = "List, set or show the named fan profiles")]
+    Profile {
+        #[command(subcommand)]
+        action: ProfileCommand,
+    },
     #[command(about = "Print this program's version")]
     Version,
 }
 
+#[derive(Subcommand, Debug)]
+pub enum FanCommand {
+    #[command(
+        about = "Return the fan to auto after <SECS> seconds without commands (0 disables it, max 120)"
+    )]
+    Watchdog { secs: u8 },
+    #[command(about = "Enable the fan")]
+    Enable,
+    #[command(about = "Disable the fan")]
+    Disable,
+    #[command(about = "Hand the fan back to tpfand's curve after setting a level")]
+    Resume,
+    #[command(about = "Go to the next fan level (auto, 1-7, full-speed, then auto again)")]
+    Cycle {
+        #[arg(short, long, help = "go to the previous level instead")]
+        reverse: bool,
+    },
+    /// Started by `fan <level> --for`, see `tpfanctl::timed::Overrides::hold`.
+    #[command(hide = true)]
+    Hold {
+        #[arg(long, value_parser = |s: &str| libtpfs::FanSpeed::from_string(s.to_string()))]
+        level: libtpfs::FanSpeed,
+        #[arg(long, value_parser = |s: &str| libtpfs::FanSpeed::from_reading(s.to_string()))]
+        previous: libtpfs::FanSpeed,
+        #[arg(long)]
+        watchdog: u8,
+        #[arg(long)]
+        until: u64,
+        #[arg(long, default_value = timed::STATE_PATH)]
+        state: std::path::PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProfileCommand {
+    #[command(about = "List the profiles, marking the active one")]
+    List,
+    #[command(about = "Switch to a profile, also after a reboot")]
+    Set { name: String },
+    #[command(about = "Print a profile, or the active one")]
+    Show { name: Option<String> },
+    #[command(about = "Set the saved profile again (at boot, when tpfand isn't used)")]
+    Restore,
+}
+
 /// NOTE: The `version` option is not used, as it will be customized
 #[derive(Parser, Debug)]
 #[command(author, about = "A simple ThinkPad Fan control tool. pass `-h` for help.", long_about = None)]
@@ -38,6 +156,38 @@ struct Args {
         help = "do not pretty-print data."
     )]
     disable_pretty_print: bool,
+
+    #[arg(
+        short = 'f',
+        long,
+        default_value_t = OutputFormat::default(),
+        value_parser = |s: &str| OutputFormat::from_string(s.to_string()),
+        help = "how to print data (text, json, csv). json implies --json-errors."
+    )]
+    format: OutputFormat,
+
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "print errors as JSON (kind, exit_code, message, description, help)."
+    )]
+    json_errors: bool,
+
+    #[arg(
+        short = 's',
+        long,
+        default_value_t = libtpfs::TempSource::default(),
+        value_parser = |s: &str| libtpfs::TempSource::from_string(s.to_string()),
+        help = "where to read temperatures from (hwmon, sensors)."
+    )]
+    temp_source: libtpfs::TempSource,
+
+    #[arg(
+        long,
+        default_value = libtpfs::ipc::DEFAULT_SOCKET_PATH,
+        help = "go through tpfand on this socket, if it is running."
+    )]
+    socket: std::path::PathBuf,
 }
 
 fn main() {
@@ -47,24 +197,87 @@ fn main() {
 
     PRINT_ERRORS.set(args.quiet).unwrap();
     PRETTY_PRINT.set(!args.disable_pretty_print).unwrap();
+    JSON_ERRORS
+        .set(args.json_errors || args.format == OutputFormat::Json)
+        .unwrap();
+    FORMAT.set(args.format).unwrap();
+    TEMP_SOURCE.set(args.temp_source).unwrap();
+    DAEMON_SOCKET.set(args.socket).unwrap();
 
     let app = Application::new();
 
     match args.command {
         Command::Dash => app.dash(),
-        Command::Temp => app.get_temp(),
-        Command::Fan { fanspeed } => match fanspeed {
+        Command::Temp { all } => {
+            if all {
+                app.get_all_temps()
+            } else {
+                app.get_temp()
+            }
+        }
+        Command::Fan {
+            action: Some(action),
+            ..
+        } => match action {
+            FanCommand::Watchdog { secs } => app.set_watchdog(secs),
+            FanCommand::Enable => app.enable_fan(),
+            FanCommand::Disable => app.disable_fan(),
+            FanCommand::Resume => app.resume(),
+            FanCommand::Cycle { reverse } => app.cycle_fan(reverse),
+            FanCommand::Hold {
+                level,
+                previous,
+                watchdog,
+                until,
+                state,
+            } => timed::Overrides::with_backend(state, libtpfs::backend::ProcfsBackend::default())
+                .hold(level, previous, watchdog, until)
+                .unwrap_or_else(|e| err(e)),
+        },
+        Command::Fan {
+            action: None,
+            pwm: Some(pwm),
+            ..
+        } => app.set_pwm(pwm),
+        Command::Fan {
+            action: None,
+            fanspeed,
+            fan,
+            pwm: None,
+            duration,
+        } => match fanspeed {
+            Some(fs) if fan.is_none() && duration.is_none() => app.set_fan_or_profile(fs),
             Some(fs) => {
-                let res = libtpfs::FanSpeed::from_string(fs);
+                let fs = libtpfs::FanSpeed::from_string(fs).unwrap_or_else(|e| err(e));
 
-                match res {
-                    Ok(fs) => app.set_fan(fs),
-                    Err(e) => err(e),
+                match (fan, duration) {
+                    (_, Some(duration)) => app.set_fan_timed(fs, duration),
+                    (Some(fan), None) => app.set_fan_for(fan, fs),
+                    (None, None) => app.set_fan(fs),
                 }
             }
             None => app.get_fan(),
         },
         Command::Rpm => app.get_rpm(),
+        Command::Status { template: None } => app.status(),
+        Command::Status {
+            template: Some(template),
+        } => app.status_template(&template),
+        Command::Bar {
+            protocol,
+            interval,
+            template,
+        } => app.bar(
+            protocol,
+            std::time::Duration::from_secs(interval.max(1)),
+            &template,
+        ),
+        Command::Profile { action } => match action {
+            ProfileCommand::List => app.list_profiles(),
+            ProfileCommand::Set { name } => app.set_profile(&name),
+            ProfileCommand::Show { name } => app.show_profile(name),
+            ProfileCommand::Restore => app.restore_profile(),
+        },
         Command::Version => version(),
     }
 }
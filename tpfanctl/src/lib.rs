@@ -1,26 +1,74 @@
-use std::{sync::OnceLock, time::Duration};
+pub mod bar;
+pub mod output;
+pub mod profile;
+pub mod template;
+pub mod timed;
+
+use std::{path::PathBuf, sync::OnceLock, time::Duration};
 
 use color_eyre::owo_colors::OwoColorize;
 use libtpfanspeed as libtpfs;
+use libtpfs::{
+    config::{Config, DEFAULT_CONFIG_PATH},
+    failsafe::{Failsafe, FailsafePolicy},
+    guard::{self, FanGuard},
+    ipc,
+};
+use output::{OutputFormat, Snapshot, Status};
 
 pub static VERSION: &str = "0.2.0";
 pub static PRINT_ERRORS: OnceLock<bool> = OnceLock::new();
 pub static PRETTY_PRINT: OnceLock<bool> = OnceLock::new();
+pub static TEMP_SOURCE: OnceLock<libtpfs::TempSource> = OnceLock::new();
+pub static DAEMON_SOCKET: OnceLock<PathBuf> = OnceLock::new();
+pub static JSON_ERRORS: OnceLock<bool> = OnceLock::new();
+pub static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+/// The order `fan cycle` (and clicking on the bar) goes through the levels in.
+/// Level 0 stops the fan, so it is left out for a stray click not to.
+const CYCLE: [libtpfs::FanSpeed; 9] = [
+    libtpfs::FanSpeed::Auto,
+    libtpfs::FanSpeed::Level(1),
+    libtpfs::FanSpeed::Level(2),
+    libtpfs::FanSpeed::Level(3),
+    libtpfs::FanSpeed::Level(4),
+    libtpfs::FanSpeed::Level(5),
+    libtpfs::FanSpeed::Level(6),
+    libtpfs::FanSpeed::Level(7),
+    libtpfs::FanSpeed::FullSpeed,
+];
 
 pub fn version() {
     info(format!("tpfanctl version {}", VERSION.cyan().bold()))
 }
 
+/// Print `err` (as JSON with `--json-errors`) and exit with its
+/// [`libtpfs::error::ErrorKind::exit_code`],
+/// after putting back any level a [`FanGuard`] would have.
 pub fn err(err: libtpfs::error::Error) -> ! {
-    if *PRINT_ERRORS.get().unwrap() {
-        eprintln!("{}{}", "==> ERROR: ".red().bold(), err);
+    let code = err.kind().exit_code();
+    let _ = guard::restore_all();
 
-        if let Some(help) = err.help() {
-            eprintln!("{}{}", "==> HELP: ".green().bold(), help)
+    if *PRINT_ERRORS.get().unwrap() {
+        if JSON_ERRORS.get().copied().unwrap_or_default() {
+            let json = serde_json::json!({
+                "kind": err.kind(),
+                "exit_code": code,
+                "message": err.to_string(),
+                "description": err.desc(),
+                "help": err.help(),
+            });
+            eprintln!("{json}");
+        } else {
+            eprintln!("{}{}", "==> ERROR: ".red().bold(), err);
+
+            if let Some(help) = err.help() {
+                eprintln!("{}{}", "==> HELP: ".green().bold(), help)
+            }
         }
     }
 
-    std::process::exit(1);
+    std::process::exit(code);
 }
 
 pub fn info<S: AsRef<str>>(txt: S) {
@@ -30,23 +78,115 @@ pub fn info<S: AsRef<str>>(txt: S) {
 #[derive(Default, Clone)]
 pub struct Application {
     pretty_print: bool,
+    format: OutputFormat,
+    temp_source: libtpfs::TempSource,
+    /// A running tpfand, which `fan`, `temp` and `rpm` go through instead of the hardware.
+    daemon: Option<ipc::Client>,
 }
 
 impl Application {
     pub fn new() -> Self {
+        let socket = DAEMON_SOCKET
+            .get()
+            .cloned()
+            .unwrap_or_else(|| PathBuf::from(ipc::DEFAULT_SOCKET_PATH));
+
+        let format = FORMAT.get().copied().unwrap_or_default();
+
         Self {
-            pretty_print: *PRETTY_PRINT.get().unwrap(),
+            // the other formats are for scripts, which don't want to be chatted to either
+            pretty_print: *PRETTY_PRINT.get().unwrap() && format == OutputFormat::Text,
+            format,
+            temp_source: TEMP_SOURCE.get().copied().unwrap_or_default(),
+            daemon: ipc::Client::connect(socket),
+        }
+    }
+
+    fn temps(&self) -> Result<libtpfs::Temperatures, libtpfs::error::Error> {
+        match &self.daemon {
+            Some(daemon) => daemon.temps(),
+            None => libtpfs::get_temps_from(self.temp_source),
+        }
+    }
+
+    fn sensor_readings(&self) -> Result<Vec<libtpfs::SensorReading>, libtpfs::error::Error> {
+        match &self.daemon {
+            Some(daemon) => daemon.sensors(),
+            None => libtpfs::get_sensor_readings_from(self.temp_source),
+        }
+    }
+
+    fn fans(&self) -> Result<Vec<libtpfs::FanReading>, libtpfs::error::Error> {
+        match &self.daemon {
+            Some(daemon) => daemon.fans(),
+            None => libtpfs::get_fans(),
+        }
+    }
+
+    /// The fan status, and what tpfand is doing with it if it's running.
+    fn fan_status(
+        &self,
+    ) -> Result<(libtpfs::FanStatus, Option<ipc::ControlMode>), libtpfs::error::Error> {
+        match &self.daemon {
+            Some(daemon) => daemon
+                .status()
+                .map(|status| (status.fan, Some(status.control))),
+            None => libtpfs::get_fan_status().map(|status| (status, None)),
+        }
+    }
+
+    fn set_level(
+        &self,
+        fanspeed: libtpfs::FanSpeed,
+        fan: Option<u8>,
+    ) -> Result<(), libtpfs::error::Error> {
+        match (&self.daemon, fan) {
+            (Some(daemon), fan) => daemon.set_level(fanspeed, fan),
+            (None, Some(fan)) => {
+                Self::by_hand().and_then(|_| libtpfs::set_fanspeed_for(fan, fanspeed))
+            }
+            (None, None) => Self::by_hand().and_then(|_| libtpfs::set_fanspeed(fanspeed)),
+        }
+    }
+
+    /// Before setting the fan by hand without tpfand: stop any `--for`, and
+    /// forget the profile, which no longer decides the level.
+    fn by_hand() -> Result<(), libtpfs::error::Error> {
+        timed::Overrides::new().cancel()?;
+        libtpfs::profile::clear_active(Self::profile_state())
+    }
+
+    /// How long a `fan <level> --for` has left, and what comes after it.
+    fn override_remaining(&self) -> Option<(Duration, String)> {
+        match &self.daemon {
+            Some(daemon) => {
+                let status = daemon.status().ok()?;
+                let then = match (status.after_expiry, status.profile) {
+                    (Some(ipc::ControlMode::Manual { level }), _) => level.to_string(),
+                    (_, Some(profile)) => format!("the {profile} profile"),
+                    (_, None) => "tpfand's curve".to_string(),
+                };
+
+                status
+                    .expires_in
+                    .map(|secs| (Duration::from_secs(secs), then))
+            }
+            None => timed::Overrides::new()
+                .active()
+                .map(|active| (active.remaining(), active.previous.to_string())),
         }
     }
 
-    fn get_temp_color(temp: u8) -> color_eyre::owo_colors::AnsiColors {
+    pub(crate) fn get_temp_color(
+        temp: libtpfs::MilliCelsius,
+    ) -> color_eyre::owo_colors::AnsiColors {
         // Temperature ranges include:
         // >40: blue
         // 40-55: green
         // 55-75: yellow
         // >75: red
 
-        match temp {
+        match temp.degrees() {
             ..40 => color_eyre::owo_colors::AnsiColors::Blue,
             40..=55 => color_eyre::owo_colors::AnsiColors::Green,
             56..=75 => color_eyre::owo_colors::AnsiColors::Yellow,
@@ -54,8 +194,8 @@ impl Application {
         }
     }
 
-    fn print_temp_progress_bar(temp: u8) {
-        let filled_frac = temp as f64 / 100.0;
+    fn print_temp_progress_bar(temp: libtpfs::MilliCelsius) {
+        let filled_frac = temp.as_celsius() / 100.0;
         let mut nsquares = (filled_frac * 20.0) as u8;
 
         if nsquares > 20 {
@@ -73,7 +213,23 @@ impl Application {
     }
 
     pub fn get_temp(&self) {
-        let temps = libtpfs::get_temps().unwrap_or_else(|e| err(e));
+        let temps = self.temps().unwrap_or_else(|e| err(e));
+
+        match self.format {
+            OutputFormat::Json => {
+                let snapshot = Snapshot {
+                    temps: Some(temps),
+                    ..Default::default()
+                };
+                println!("{}", snapshot.to_json());
+                return;
+            }
+            OutputFormat::Csv => {
+                print!("{}", Snapshot::temps_csv(&temps));
+                return;
+            }
+            OutputFormat::Text => (),
+        }
 
         if !self.pretty_print {
             println!("{temps}");
@@ -87,35 +243,145 @@ impl Application {
             avg.color(Application::get_temp_color(avg))
         );
 
-        for (key, value) in temps.cores {
-            print!("Core {}: ", key.green());
-            Application::print_temp_progress_bar(value.temp);
+        // only group by package on multi-socket machines, the average says it all otherwise
+        let grouped = temps.packages.len() > 1;
+        let indent = if grouped { "  " } else { "" };
+
+        for (package_id, package) in temps.packages {
+            if grouped {
+                let temp = package.temp.unwrap_or_default();
+                println!(
+                    "{} {} temperature: {}°C",
+                    "Package".green().bold(),
+                    package_id.green().bold(),
+                    temp.color(Application::get_temp_color(temp))
+                );
+            }
+
+            for (key, value) in package.cores {
+                match value.core_type {
+                    libtpfs::CoreType::Performance => print!("{indent}Core {} (P): ", key.green()),
+                    libtpfs::CoreType::Efficiency => print!("{indent}Core {} (E): ", key.green()),
+                    libtpfs::CoreType::Unknown => print!("{indent}Core {}: ", key.green()),
+                }
+                Application::print_temp_progress_bar(value.temp);
+                println!(
+                    " ({}°C)",
+                    value.temp.color(Application::get_temp_color(value.temp))
+                );
+            }
+
+            for (key, value) in package.dies {
+                print!("{indent}CCD {}: ", key.green());
+                Application::print_temp_progress_bar(value.temp);
+                println!(
+                    " ({}°C)",
+                    value.temp.color(Application::get_temp_color(value.temp))
+                );
+            }
+        }
+    }
+
+    pub fn get_all_temps(&self) {
+        let readings = self.sensor_readings().unwrap_or_else(|e| err(e));
+
+        match self.format {
+            OutputFormat::Json => {
+                let snapshot = Snapshot {
+                    sensors: Some(readings),
+                    ..Default::default()
+                };
+                println!("{}", snapshot.to_json());
+                return;
+            }
+            OutputFormat::Csv => {
+                print!("{}", Snapshot::sensors_csv(&readings));
+                return;
+            }
+            OutputFormat::Text => (),
+        }
+
+        if !self.pretty_print {
+            for reading in readings {
+                println!("{reading}");
+            }
+            return;
+        }
+
+        let mut last_chip = None;
+        for reading in readings {
+            if last_chip.as_ref() != Some(&reading.chip) {
+                println!("{}", reading.chip.green().bold());
+                last_chip = Some(reading.chip.clone());
+            }
+
+            print!("  {}: ", reading.label.green());
+            Application::print_temp_progress_bar(reading.temp);
             println!(
                 " ({}°C)",
-                value.temp.color(Application::get_temp_color(value.temp))
+                reading
+                    .temp
+                    .color(Application::get_temp_color(reading.temp))
             );
         }
     }
 
     pub fn get_rpm(&self) {
-        let rpm = libtpfs::get_rpm().unwrap_or_else(|e| err(e));
+        let fans = self.fans().unwrap_or_else(|e| err(e));
+
+        match self.format {
+            OutputFormat::Json => {
+                let snapshot = Snapshot {
+                    fans: Some(fans),
+                    ..Default::default()
+                };
+                println!("{}", snapshot.to_json());
+                return;
+            }
+            OutputFormat::Csv => {
+                print!("{}", Snapshot::fans_csv(&fans));
+                return;
+            }
+            OutputFormat::Text => (),
+        }
 
         if !self.pretty_print {
-            println!("{rpm}");
+            for fan in fans {
+                println!("{}", fan.rpm);
+            }
             return;
         }
 
-        println!(
-            "Your fan is spinning at {} {}",
-            rpm.green().bold(),
-            "RPM".bold()
-        );
+        if let [fan] = fans.as_slice() {
+            println!(
+                "Your fan is spinning at {} {}",
+                fan.rpm.green().bold(),
+                "RPM".bold()
+            );
+            return;
+        }
+
+        for fan in fans {
+            println!(
+                "Fan {} is spinning at {} {}",
+                fan.id.green(),
+                fan.rpm.green().bold(),
+                "RPM".bold()
+            );
+        }
     }
 
     pub fn set_fan(&self, fanspeed: libtpfs::FanSpeed) {
-        let curr_fanspeed = libtpfs::get_fanspeed().unwrap_or_else(|e| err(e));
-
-        if fanspeed.to_string() == curr_fanspeed && self.pretty_print {
+        let (status, _) = self.fan_status().unwrap_or_else(|e| err(e));
+        let curr_fanspeed = status.level;
+
+        // tpfand always gets it, to leave its curve for this level; setting it again
+        // still ends a `--for`
+        if self.daemon.is_none()
+            && fanspeed.is_same_as(curr_fanspeed)
+            && self.pretty_print
+            && self.override_remaining().is_none()
+        {
             info(format!(
                 "Your current fan speed is already {}!",
                 fanspeed.yellow().bold()
@@ -123,7 +389,7 @@ impl Application {
             return;
         }
 
-        libtpfs::set_fanspeed(fanspeed).unwrap_or_else(|e| err(e));
+        self.set_level(fanspeed, None).unwrap_or_else(|e| err(e));
 
         if self.pretty_print {
             info(format!(
@@ -133,26 +399,99 @@ impl Application {
         }
     }
 
+    /// Set `fanspeed` for `duration` only, see [`timed`].
+    pub fn set_fan_timed(&self, fanspeed: libtpfs::FanSpeed, duration: Duration) {
+        let then = match &self.daemon {
+            Some(daemon) => daemon.set_level_timed(fanspeed, None, duration).map(|_| {
+                self.override_remaining()
+                    .map_or_else(|| "tpfand's curve".to_string(), |(_, then)| then)
+            }),
+            None => timed::Overrides::new()
+                .start(fanspeed, duration)
+                .map(|state| state.previous.to_string()),
+        }
+        .unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            info(format!(
+                "Your fan speed was set to {} for {}, then back to {}",
+                fanspeed.yellow().bold(),
+                timed::format_duration(duration).yellow().bold(),
+                then.yellow().bold()
+            ))
+        }
+    }
+
+    pub fn set_pwm(&self, pwm: u8) {
+        // tpfand only deals in levels, which is all the EC knows anyway
+        match &self.daemon {
+            Some(daemon) => daemon.set_level(libtpfs::FanSpeed::from_pwm(pwm), None),
+            None => Self::by_hand().and_then(|_| libtpfs::set_pwm(pwm)),
+        }
+        .unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            info(format!(
+                "Your fan PWM was set to {} (level {})",
+                pwm.yellow().bold(),
+                libtpfs::FanSpeed::from_pwm(pwm).yellow().bold()
+            ))
+        }
+    }
+
+    pub fn set_fan_for(&self, fan: u8, fanspeed: libtpfs::FanSpeed) {
+        self.set_level(fanspeed, Some(fan))
+            .unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            info(format!(
+                "The speed of fan {} was set to {}",
+                fan.yellow().bold(),
+                fanspeed.yellow().bold()
+            ))
+        }
+    }
+
     pub fn get_fan(&self) {
-        let fanspeed = libtpfs::get_fanspeed().unwrap_or_else(|e| err(e));
+        let (status, control) = self.fan_status().unwrap_or_else(|e| err(e));
+
+        match self.format {
+            OutputFormat::Json => {
+                let snapshot = Snapshot {
+                    fan: Some(status),
+                    control,
+                    ..Default::default()
+                };
+                println!("{}", snapshot.to_json());
+                return;
+            }
+            OutputFormat::Csv => {
+                print!("{}", Snapshot::fan_csv(&status, control.as_ref()));
+                return;
+            }
+            OutputFormat::Text => (),
+        }
+
+        let fanspeed = status.level;
 
         if !self.pretty_print {
             println!("{fanspeed}");
             return;
         }
 
+        use libtpfs::FanSpeed as F;
         let levels = [
-            "auto",
-            "0",
-            "1",
-            "2",
-            "3",
-            "4",
-            "5",
-            "6",
-            "7",
-            "full-speed",
-            "disengaged",
+            F::Auto,
+            F::Level(0),
+            F::Level(1),
+            F::Level(2),
+            F::Level(3),
+            F::Level(4),
+            F::Level(5),
+            F::Level(6),
+            F::Level(7),
+            F::FullSpeed,
+            F::Disengaged,
         ];
 
         // auto, 0, 1, 2, 3, 4, 5, 6, 7, full-speed, disengaged
@@ -176,7 +515,7 @@ impl Application {
             }
         }
 
-        let fanspeed_chars = fanspeed.chars().collect::<Vec<char>>();
+        let fanspeed_chars = fanspeed.to_string().chars().collect::<Vec<char>>();
         for c in " A 0 1 2 3 4 5 6 7 F D ".chars() {
             // TODO: find a better way to do this??
             if fanspeed_chars[0].to_ascii_uppercase() == c {
@@ -187,6 +526,287 @@ impl Application {
         }
 
         println!();
+
+        match status.watchdog {
+            Some(0) => println!("Watchdog: {}", "off".dimmed()),
+            Some(timeout) => println!("Watchdog: {}s", timeout.yellow().bold()),
+            None => (),
+        }
+
+        if let Some(control) = control {
+            println!("Controlled by tpfand: {}", control.cyan().bold());
+        }
+
+        if let Some((remaining, then)) = self.override_remaining() {
+            println!(
+                "Back to {} in {}",
+                then.yellow().bold(),
+                timed::format_duration(remaining).yellow().bold()
+            );
+        }
+    }
+
+    /// Read the temperatures and the fan, once.
+    pub fn read_status(&self) -> Result<Status, libtpfs::error::Error> {
+        let temps = self.temps()?;
+        let (fan, control) = self.fan_status()?;
+        let fans = self.fans()?;
+
+        Ok(Status {
+            temps,
+            fan,
+            fans,
+            control,
+        })
+    }
+
+    pub fn status(&self) {
+        let status = self.read_status().unwrap_or_else(|e| err(e));
+
+        match self.format {
+            OutputFormat::Json => return println!("{}", Snapshot::from(status).to_json()),
+            OutputFormat::Csv => {
+                println!("{}", status.csv_header());
+                return println!("{}", status.to_csv_row());
+            }
+            OutputFormat::Text => (),
+        }
+
+        let Status {
+            temps,
+            fan,
+            fans,
+            control,
+        } = status;
+
+        if !self.pretty_print {
+            println!("Average: {}°C", temps.avg);
+            println!("Level: {}", fan.level);
+            for fan in fans {
+                println!("Fan {}: {} RPM", fan.id, fan.rpm);
+            }
+            if let Some(control) = control {
+                println!("Control: {control}");
+            }
+            return;
+        }
+
+        println!(
+            "{} {}°C",
+            "Temperature:".green().bold(),
+            temps.avg.color(Application::get_temp_color(temps.avg))
+        );
+        println!(
+            "{} {}",
+            "Fan speed:".green().bold(),
+            fan.level.yellow().bold()
+        );
+        for fan in fans {
+            println!(
+                "{} {} {}",
+                format!("Fan {}:", fan.id).green().bold(),
+                fan.rpm.bold(),
+                "RPM".bold()
+            );
+        }
+        if let Some(control) = control {
+            println!(
+                "{} {}",
+                "Controlled by tpfand:".green().bold(),
+                control.cyan().bold()
+            );
+        }
+    }
+
+    /// `status --template`, see [`template`].
+    pub fn status_template(&self, template: &str) {
+        let template = template::Template::parse(template).unwrap_or_else(|e| err(e));
+        let line = template
+            .render(&self.read_status().unwrap_or_else(|e| err(e)))
+            .unwrap_or_else(|e| err(e));
+
+        println!("{line}");
+    }
+
+    /// Set the level after (or before) the current one in [`CYCLE`], and return it.
+    pub(crate) fn cycle_level(
+        &self,
+        reverse: bool,
+    ) -> Result<libtpfs::FanSpeed, libtpfs::error::Error> {
+        let (status, _) = self.fan_status()?;
+
+        // full-speed reads back as disengaged; from level 0, start over from auto
+        let idx = CYCLE.iter().position(|l| l.is_same_as(status.level));
+        let next = match (idx, reverse) {
+            (None, _) => CYCLE[0],
+            (Some(idx), false) => CYCLE[(idx + 1) % CYCLE.len()],
+            (Some(idx), true) => CYCLE[(idx + CYCLE.len() - 1) % CYCLE.len()],
+        };
+
+        self.set_level(next, None)?;
+        Ok(next)
+    }
+
+    /// Hand the fan back to tpfand's curve, or to the EC if it isn't running.
+    pub(crate) fn release(&self) -> Result<(), libtpfs::error::Error> {
+        match &self.daemon {
+            Some(daemon) => daemon.resume(),
+            None => Self::by_hand().and_then(|_| libtpfs::set_fanspeed(libtpfs::FanSpeed::Auto)),
+        }
+    }
+
+    pub fn cycle_fan(&self, reverse: bool) {
+        let level = self.cycle_level(reverse).unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            info(format!(
+                "Your fan speed was set to {}",
+                level.yellow().bold()
+            ))
+        }
+    }
+
+    pub fn resume(&self) {
+        let Some(daemon) = &self.daemon else {
+            err(libtpfs::err!(
+                Unsupported,
+                "Start tpfand first",
+                "tpfand is not running"
+            ))
+        };
+
+        daemon.resume().unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            info("tpfand is back in control of the fan")
+        }
+    }
+
+    /// Refuse to `action` behind tpfand's back, which would undo it on its next tick.
+    fn without_daemon(&self, action: &str) -> Result<(), libtpfs::error::Error> {
+        match self.daemon {
+            Some(_) => Err(libtpfs::err!(
+                Unsupported,
+                "Stop tpfand first, or use `tpfanctl fan` and `tpfanctl resume`",
+                "Can't {} while tpfand is running",
+                action
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn set_watchdog(&self, timeout: u8) {
+        self.without_daemon("set the watchdog")
+            .and_then(|_| timed::Overrides::new().cancel())
+            .and_then(|_| libtpfs::set_watchdog(timeout))
+            .unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            match timeout {
+                0 => info("The fan watchdog was disabled"),
+                _ => info(format!(
+                    "The fan will return to auto after {}s without commands",
+                    timeout.yellow().bold()
+                )),
+            }
+        }
+    }
+
+    pub fn enable_fan(&self) {
+        self.without_daemon("enable the fan")
+            .and_then(|_| Self::by_hand())
+            .and_then(|_| libtpfs::enable_fan())
+            .unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            info("The fan was enabled")
+        }
+    }
+
+    pub fn disable_fan(&self) {
+        self.without_daemon("disable the fan")
+            .and_then(|_| Self::by_hand())
+            .and_then(|_| libtpfs::disable_fan())
+            .unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            info("The fan was disabled")
+        }
+    }
+
+    /// tpfand's config, for when tpfand isn't running. `None` if there is none.
+    pub(crate) fn local_config() -> Result<Option<Config>, libtpfs::error::Error> {
+        if !std::path::Path::new(DEFAULT_CONFIG_PATH).exists() {
+            return Ok(None);
+        }
+
+        Config::load(DEFAULT_CONFIG_PATH).map(Some)
+    }
+
+    /// Where the profile set last is kept: tpfand's `profile_state`, or the
+    /// default if its config is missing (or broken, which is reported elsewhere).
+    pub(crate) fn profile_state() -> PathBuf {
+        match Self::local_config() {
+            Ok(Some(config)) => config.profile_state,
+            _ => PathBuf::from(libtpfs::profile::STATE_PATH),
+        }
+    }
+
+    /// The `[overheat]` policy from tpfand's config, or the default one without a config.
+    fn overheat_policy() -> FailsafePolicy {
+        Self::local_config()
+            .map(|config| config.map(|config| config.overheat).unwrap_or_default())
+            .unwrap_or_else(|e| err(e))
+    }
+
+    /// Apply the overheat failsafe for `dash`, unless tpfand is running and
+    /// does it already. `guard` goes back to the previous level once cooled down.
+    fn check_overheat(
+        &self,
+        failsafe: &mut Failsafe,
+        guard: &mut Option<FanGuard>,
+    ) -> Result<Option<libtpfs::failsafe::Trip>, libtpfs::error::Error> {
+        if let Some(daemon) = &self.daemon {
+            return Ok(daemon.status()?.failsafe);
+        }
+
+        let was_tripped = failsafe.trip().is_some();
+        failsafe.update(&self.temps()?);
+
+        let current = libtpfs::get_fan_status()?.level;
+        let level = failsafe.policy().level();
+
+        match failsafe.trip() {
+            Some(_) if !current.is_same_as(level) => {
+                // either it just tripped, or someone set a level since, restore that one later
+                if let Some(old) = guard.take() {
+                    old.disarm();
+                }
+                guard.insert(FanGuard::with_previous(current)).set(level)?;
+            }
+            None if was_tripped => {
+                if let Some(guard) = guard.take() {
+                    guard.restore()?;
+                }
+            }
+            _ => (),
+        }
+
+        Ok(failsafe.trip().cloned())
+    }
+
+    fn print_overheat(&self, failsafe: &mut Failsafe, guard: &mut Option<FanGuard>) {
+        let level = failsafe.policy().level();
+
+        match self.check_overheat(failsafe, guard) {
+            Ok(None) => println!("Overheat failsafe: {}", "off".dimmed()),
+            Ok(Some(trip)) => println!(
+                "Overheat failsafe: {}, forcing {}",
+                trip.red().bold(),
+                level.yellow().bold()
+            ),
+            Err(e) => println!("Overheat failsafe: {}", e.red().bold()),
+        }
     }
 
     pub fn get_dash_once(&self) {
@@ -204,9 +824,23 @@ impl Application {
     pub fn dash(&self) {
         const ESC: char = 27 as char;
 
+        if self.format != OutputFormat::Text {
+            err(libtpfs::err!(
+                Unsupported,
+                "Use `tpfanctl status` to poll instead",
+                "The dashboard can't be printed as {}",
+                self.format
+            ))
+        }
+
+        // only one signal handler per process, so this one undoes the failsafe's guard too
+        guard::install_panic_hook();
         ctrlc::set_handler(move || {
             // exit alt mode
             print!("{ESC}[?1049l");
+            if let Err(e) = guard::restore_all() {
+                eprintln!("{}{}", "==> ERROR: ".red().bold(), e);
+            }
             info("exiting...");
             std::process::exit(0);
         })
@@ -215,7 +849,11 @@ impl Application {
         // enter alt mode, clear screen, go to 0,0
         print!("{ESC}[?1049h{ESC}[2J{ESC}[H");
 
+        let mut failsafe = Failsafe::new(Application::overheat_policy());
+        let mut overheat_guard = None;
+
         self.get_dash_once();
+        self.print_overheat(&mut failsafe, &mut overheat_guard);
         std::thread::sleep(Duration::from_secs(1));
 
         loop {
@@ -234,6 +872,10 @@ impl Application {
             print!("{ESC}[1E{ESC}[24C{ESC}[2K\r");
             self.get_rpm();
 
+            // skip the separator, clear the failsafe line
+            print!("{ESC}[1E{ESC}[2K");
+            self.print_overheat(&mut failsafe, &mut overheat_guard);
+
             std::thread::sleep(Duration::from_secs(1));
         }
     }
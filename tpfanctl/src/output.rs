@@ -0,0 +1,297 @@
+//! Machine-readable output, for `--format json` and `--format csv`.
+//!
+//! Every JSON document is one [`Snapshot`] with only the fields the subcommand
+//! reads, tagged with [`SCHEMA_VERSION`]. CSV has a header row naming the columns.
+
+use libtpfanspeed as libtpfs;
+use libtpfs::{
+    err, error::Error, generic_err, ipc::ControlMode, FanReading, FanStatus, SensorReading,
+    Temperatures,
+};
+use serde::Serialize;
+use std::fmt::Write as _;
+
+/// Bumped whenever a field is renamed, removed or changes meaning. New fields
+/// don't bump it, so ignore the ones you don't know.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How `temp`, `rpm`, `fan` and `status` print what they read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// For humans, see `--disable-pretty-print`.
+    #[default]
+    Text,
+    Json,
+    Csv,
+}
+
+impl OutputFormat {
+    pub fn from_string(value: String) -> Result<Self, Error> {
+        match value.as_ref() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => Err(err!(
+                InvalidValue,
+                "Valid output formats are text, json and csv",
+                "{} is an invalid output format",
+                value
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Text => "text",
+            Self::Json => "json",
+            Self::Csv => "csv",
+        };
+
+        write!(f, "{s}")
+    }
+}
+
+/// Everything one invocation read. Temperatures are in millidegrees Celsius,
+/// like in [`libtpfs::MilliCelsius`].
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub schema: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temps: Option<Temperatures>,
+    /// Every temperature sensor, only from `temp --all`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensors: Option<Vec<SensorReading>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fan: Option<FanStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fans: Option<Vec<FanReading>>,
+    /// What tpfand is doing with the fan, absent if it isn't running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control: Option<ControlMode>,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self {
+            schema: SCHEMA_VERSION,
+            temps: None,
+            sensors: None,
+            fan: None,
+            fans: None,
+            control: None,
+        }
+    }
+}
+
+/// Everything `status` reads, all at once: what templates and the bar show.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub temps: Temperatures,
+    pub fan: FanStatus,
+    pub fans: Vec<FanReading>,
+    /// What tpfand is doing with the fan, `None` if it isn't running.
+    pub control: Option<ControlMode>,
+}
+
+impl From<Status> for Snapshot {
+    fn from(status: Status) -> Self {
+        Self {
+            temps: Some(status.temps),
+            fan: Some(status.fan),
+            fans: Some(status.fans),
+            control: status.control,
+            ..Default::default()
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        // only fails on maps with non-string keys, which serde_json stringifies for integers
+        serde_json::to_string(self).unwrap_or_else(|e| crate::err(generic_err!(e)))
+    }
+
+    /// One row for each package, core and die:
+    /// `package,sensor,id,label,type,temp_celsius,max_celsius,critical_celsius`.
+    pub fn temps_csv(temps: &Temperatures) -> String {
+        let mut out =
+            "package,sensor,id,label,type,temp_celsius,max_celsius,critical_celsius\n".to_string();
+
+        for (package_id, package) in &temps.packages {
+            if let Some(temp) = package.temp {
+                let _ = writeln!(out, "{package_id},package,{package_id},,,{temp},,");
+            }
+            for (kind, readings) in [("core", &package.cores), ("die", &package.dies)] {
+                for (id, reading) in readings {
+                    let _ = writeln!(
+                        out,
+                        "{package_id},{kind},{id},{},{},{},{},{}",
+                        field(&reading.label),
+                        core_type(reading.core_type),
+                        reading.temp,
+                        reading.max,
+                        reading.critical
+                    );
+                }
+            }
+        }
+
+        out
+    }
+
+    /// `chip,label,temp_celsius,max_celsius,critical_celsius`, with empty limits if not reported.
+    pub fn sensors_csv(readings: &[SensorReading]) -> String {
+        let mut out = "chip,label,temp_celsius,max_celsius,critical_celsius\n".to_string();
+
+        for reading in readings {
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{}",
+                field(&reading.chip),
+                field(&reading.label),
+                reading.temp,
+                reading.max.map(|t| t.to_string()).unwrap_or_default(),
+                reading.critical.map(|t| t.to_string()).unwrap_or_default()
+            );
+        }
+
+        out
+    }
+
+    /// `fan,rpm`
+    pub fn fans_csv(fans: &[FanReading]) -> String {
+        let mut out = "fan,rpm\n".to_string();
+
+        for fan in fans {
+            let _ = writeln!(out, "{},{}", fan.id, fan.rpm);
+        }
+
+        out
+    }
+
+    /// `level,enabled,rpm,watchdog,control`, with an empty control if tpfand isn't running.
+    pub fn fan_csv(fan: &FanStatus, control: Option<&ControlMode>) -> String {
+        format!(
+            "level,enabled,rpm,watchdog,control\n{},{},{},{},{}\n",
+            fan.level,
+            fan.enabled,
+            fan.speed,
+            fan.watchdog.map(|w| w.to_string()).unwrap_or_default(),
+            control.map(control_csv).unwrap_or_default()
+        )
+    }
+}
+
+impl Status {
+    /// The column names of [`Status::to_csv_row`]: `avg_celsius,level,rpm,control`,
+    /// then `fanN` in RPM and `packageN`, `packageN/coreM` and `packageN/dieM` in °C.
+    pub fn csv_header(&self) -> String {
+        let (header, _): (Vec<_>, Vec<_>) = self.csv_columns().into_iter().unzip();
+        header.join(",")
+    }
+
+    /// A single row, to be appended to a log under [`Status::csv_header`].
+    pub fn to_csv_row(&self) -> String {
+        let (_, row): (Vec<_>, Vec<_>) = self.csv_columns().into_iter().unzip();
+        row.join(",")
+    }
+
+    fn csv_columns(&self) -> Vec<(String, String)> {
+        let mut columns = vec![
+            ("avg_celsius".to_string(), self.temps.avg.to_string()),
+            ("level".to_string(), self.fan.level.to_string()),
+            ("rpm".to_string(), self.fan.speed.to_string()),
+            (
+                "control".to_string(),
+                self.control.as_ref().map(control_csv).unwrap_or_default(),
+            ),
+        ];
+
+        for fan in &self.fans {
+            columns.push((format!("fan{}", fan.id), fan.rpm.to_string()));
+        }
+
+        for (package_id, package) in &self.temps.packages {
+            if let Some(temp) = package.temp {
+                columns.push((format!("package{package_id}"), temp.to_string()));
+            }
+            for (id, core) in &package.cores {
+                columns.push((
+                    format!("package{package_id}/core{id}"),
+                    core.temp.to_string(),
+                ));
+            }
+            for (id, die) in &package.dies {
+                columns.push((format!("package{package_id}/die{id}"), die.temp.to_string()));
+            }
+        }
+
+        columns
+    }
+}
+
+fn control_csv(control: &ControlMode) -> String {
+    match control {
+        ControlMode::Curve => "curve".to_string(),
+        ControlMode::Manual { level } => format!("manual:{level}"),
+    }
+}
+
+fn core_type(core_type: libtpfs::CoreType) -> &'static str {
+    match core_type {
+        libtpfs::CoreType::Performance => "P",
+        libtpfs::CoreType::Efficiency => "E",
+        libtpfs::CoreType::Unknown => "",
+    }
+}
+
+/// Quote `s` if it would break the row.
+pub(crate) fn field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtpfs::FanSpeed;
+
+    #[test]
+    fn csv_header_and_row_line_up() {
+        let sensors = r#"{
+            "coretemp-isa-0000": {
+                "Package id 0": { "temp1_input": 61.4 },
+                "Core 0": { "temp2_input": 55.0 },
+                "Core 4": { "temp3_input": 70.0 }
+            }
+        }"#;
+        let status = Status {
+            temps: libtpfs::get_temps_from_sensors_output(sensors.to_string()).unwrap(),
+            fan: FanStatus {
+                enabled: true,
+                speed: 2900,
+                level: FanSpeed::Level(3),
+                watchdog: None,
+                commands: Vec::new(),
+            },
+            fans: vec![FanReading { id: 1, rpm: 2900 }],
+            control: Some(ControlMode::Manual {
+                level: FanSpeed::Level(3),
+            }),
+        };
+
+        assert_eq!(
+            status.csv_header(),
+            "avg_celsius,level,rpm,control,fan1,package0,package0/core0,package0/core4"
+        );
+        assert_eq!(
+            status.to_csv_row(),
+            "61.4,3,2900,manual:3,2900,61.4,55.0,70.0"
+        );
+    }
+}
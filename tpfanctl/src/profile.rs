@@ -0,0 +1,212 @@
+//! `tpfanctl profile`, and `setfan <profile>`: named levels and curves, see
+//! [`libtpfs::profile`].
+//!
+//! With tpfand, the profiles come from its config and curves are followed.
+//! Without it, they come from [`libtpfs::config::DEFAULT_CONFIG_PATH`] if it
+//! exists, and only fixed levels can be set: `balanced` is `auto` then.
+
+use crate::{err, info, output, Application};
+use color_eyre::owo_colors::OwoColorize;
+use libtpfanspeed as libtpfs;
+use libtpfs::{error::Error, profile::Profile};
+use output::OutputFormat;
+use std::collections::BTreeMap;
+
+impl Application {
+    /// Every profile, and the active one.
+    fn profiles(&self) -> Result<(BTreeMap<String, Profile>, Option<String>), Error> {
+        match &self.daemon {
+            Some(daemon) => Ok((daemon.profiles()?, daemon.status()?.profile)),
+            None => {
+                let mut profiles = match Self::local_config()? {
+                    Some(config) => config.all_profiles(),
+                    None => libtpfs::profile::builtin(),
+                };
+
+                // a curve needs tpfand, without it `balanced` is the firmware's own
+                if let Some(balanced @ Profile::Curve(_)) = profiles.get_mut("balanced") {
+                    *balanced = Profile::Level(libtpfs::FanSpeed::Auto);
+                }
+
+                Ok((
+                    profiles,
+                    libtpfs::profile::load_active(Self::profile_state())?,
+                ))
+            }
+        }
+    }
+
+    /// Switch to the profile `name` and save it for the next boot.
+    pub(crate) fn apply_profile(&self, name: &str) -> Result<Profile, Error> {
+        let (profiles, _) = self.profiles()?;
+        let profile = libtpfs::profile::lookup(profiles, name)?;
+
+        match (&self.daemon, &profile) {
+            (Some(daemon), _) => daemon.set_profile(name)?,
+            (None, Profile::Level(level)) => {
+                crate::timed::Overrides::new().cancel()?;
+                libtpfs::set_fanspeed(*level)?;
+                libtpfs::profile::save_active(Self::profile_state(), name)?;
+            }
+            (None, Profile::Curve(_)) => {
+                return Err(libtpfs::err!(
+                    Unsupported,
+                    "Start tpfand, which follows curves",
+                    "The {} profile is a curve",
+                    name
+                ))
+            }
+        }
+
+        Ok(profile)
+    }
+
+    /// `setfan <value>` and `tpfanctl fan <value>`: a fan speed, or else a profile.
+    pub fn set_fan_or_profile(&self, value: String) {
+        let e = match libtpfs::FanSpeed::from_string(value.clone()) {
+            Ok(fs) => return self.set_fan(fs),
+            Err(e) => e,
+        };
+
+        // numbers out of range are fan speeds gone wrong, not profiles
+        if value.starts_with(|c: char| c.is_ascii_digit() || c == '-') {
+            err(e)
+        }
+
+        let (profiles, _) = self.profiles().unwrap_or_else(|e| err(e));
+        if !profiles.contains_key(&value) {
+            err(libtpfs::err!(
+                InvalidValue,
+                format!(
+                    "{}, or a profile: {}",
+                    e.help().unwrap_or_default(),
+                    profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+                ),
+                "{} is neither a fan speed nor a profile",
+                value
+            ))
+        }
+
+        self.set_profile(&value)
+    }
+
+    pub fn set_profile(&self, name: &str) {
+        let profile = self.apply_profile(name).unwrap_or_else(|e| err(e));
+
+        if self.pretty_print {
+            info(format!(
+                "Switched to the {} profile ({})",
+                name.yellow().bold(),
+                profile
+            ))
+        }
+    }
+
+    /// Set the saved profile again, for a boot without tpfand (which does it itself).
+    pub fn restore_profile(&self) {
+        let state = Self::profile_state();
+        let Some(name) = libtpfs::profile::load_active(state).unwrap_or_else(|e| err(e)) else {
+            if self.pretty_print {
+                info("No profile was set")
+            }
+            return;
+        };
+
+        self.set_profile(&name)
+    }
+
+    pub fn list_profiles(&self) {
+        let (profiles, active) = self.profiles().unwrap_or_else(|e| err(e));
+        let is_active = |name: &String| active.as_ref() == Some(name);
+
+        match self.format {
+            OutputFormat::Json => {
+                let json = serde_json::json!({
+                    "schema": output::SCHEMA_VERSION,
+                    "active": active,
+                    "profiles": profiles,
+                });
+                return println!("{json}");
+            }
+            OutputFormat::Csv => {
+                println!("name,type,level,active");
+                for (name, profile) in &profiles {
+                    let (kind, level) = match profile {
+                        Profile::Level(level) => ("level", level.to_string()),
+                        Profile::Curve(_) => ("curve", String::new()),
+                    };
+                    println!("{},{kind},{level},{}", output::field(name), is_active(name));
+                }
+                return;
+            }
+            OutputFormat::Text => (),
+        }
+
+        if !self.pretty_print {
+            for name in profiles.keys() {
+                println!("{name}");
+            }
+            return;
+        }
+
+        let width = profiles.keys().map(|name| name.len()).max().unwrap_or(0);
+        for (name, profile) in &profiles {
+            if is_active(name) {
+                println!("{} {:width$}  {profile}", "*".green().bold(), name.bold());
+            } else {
+                println!("  {name:width$}  {}", profile.dimmed());
+            }
+        }
+    }
+
+    /// Print the profile `name`, or the active one.
+    pub fn show_profile(&self, name: Option<String>) {
+        let (profiles, active) = self.profiles().unwrap_or_else(|e| err(e));
+
+        let Some(name) = name.or(active) else {
+            err(libtpfs::err!(
+                InvalidValue,
+                "Pick one with `tpfanctl profile set`",
+                "No profile is active"
+            ))
+        };
+        let profile = libtpfs::profile::lookup(profiles, &name).unwrap_or_else(|e| err(e));
+
+        match self.format {
+            OutputFormat::Json => {
+                let json = serde_json::json!({
+                    "schema": output::SCHEMA_VERSION,
+                    "name": name,
+                    "profile": profile,
+                });
+                return println!("{json}");
+            }
+            OutputFormat::Csv => err(libtpfs::err!(
+                Unsupported,
+                "Use --format json, or `tpfanctl profile list`",
+                "A profile can't be printed as csv"
+            )),
+            OutputFormat::Text => (),
+        }
+
+        if !self.pretty_print {
+            return println!("{name}: {profile}");
+        }
+
+        println!("Profile {}", name.yellow().bold());
+        match profile {
+            Profile::Level(level) => println!("Level: {}", level.yellow().bold()),
+            Profile::Curve(curve) => {
+                println!("Below the first point: {}", curve.base().yellow().bold());
+                for point in curve.points() {
+                    println!(
+                        "{} from {}°C, until below {}°C",
+                        point.speed.yellow().bold(),
+                        point.rising,
+                        point.falling
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,496 @@
+//! `tpfanctl status --template`, for status bars and prompts.
+//!
+//! Placeholders are written in braces and looked up in a [`Status`], see
+//! [`PLACEHOLDERS`] (which is also what `tpfanctl status --help` prints).
+
+use crate::{output::Status, Application};
+use color_eyre::owo_colors::OwoColorize;
+use libtpfanspeed as libtpfs;
+use libtpfs::{err, error::Error, ipc::ControlMode, CoreTemperature, MilliCelsius};
+
+const HELP: &str = "See `tpfanctl status --help` for the placeholders";
+
+/// The template syntax.
+pub const PLACEHOLDERS: &str =
+    "  {avg}, {hottest}            average and hottest CPU temperature in °C
+  {packageN}                  package temperature
+  {coreM}, {dieM}             core/die temperature on package 0, or {packageN/coreM}
+  {coreM.max}, {coreM.critical}, {coreM.label}, {coreM.type}
+  {rpm}, {fanN}               fan speed in RPM
+  {level}, {enabled}, {watchdog}, {control}
+
+{avg:0} rounds a temperature to 0 decimals, {avg|color} colors it like the dashboard.
+{avg>75?HOT} and {level=auto?A:M} print text depending on a value, compared as
+numbers (>, >=, <, <=, =, !=) or as text (= and !=). {{ and }} are literal braces.";
+
+/// A parsed template, see the [module docs](self).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Value {
+        key: String,
+        precision: Option<usize>,
+        color: bool,
+    },
+    Condition {
+        key: String,
+        op: Op,
+        operand: String,
+        then: String,
+        otherwise: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+/// A value looked up in a [`Status`].
+enum Value {
+    Temp(MilliCelsius),
+    Number(u64),
+    Text(String),
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => {
+                                return Err(err!(
+                                    InvalidValue,
+                                    "Write {{ for a literal brace",
+                                    "Unclosed placeholder {{{}",
+                                    placeholder
+                                ))
+                            }
+                        }
+                    }
+
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::parse(&placeholder)?);
+                }
+                '}' => {
+                    return Err(err!(
+                        InvalidValue,
+                        "Write }} for a literal brace",
+                        "Unmatched }} in {}",
+                        template
+                    ))
+                }
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        Ok(Self { parts })
+    }
+
+    pub fn render(&self, status: &Status) -> Result<String, Error> {
+        self.render_with(status, true)
+    }
+
+    /// Render without the ANSI colors of `|color`, for bars that color the text themselves.
+    pub fn render_plain(&self, status: &Status) -> Result<String, Error> {
+        self.render_with(status, false)
+    }
+
+    fn render_with(&self, status: &Status, ansi: bool) -> Result<String, Error> {
+        let mut out = String::new();
+
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => out.push_str(text),
+                Part::Value {
+                    key,
+                    precision,
+                    color,
+                } => {
+                    let value = lookup(status, key)?;
+                    let rendered = value.render(*precision);
+
+                    match (value, color) {
+                        (Value::Temp(temp), true) if ansi => out.push_str(
+                            &rendered
+                                .color(Application::get_temp_color(temp))
+                                .to_string(),
+                        ),
+                        (Value::Temp(_), true) => out.push_str(&rendered),
+                        (_, true) => {
+                            return Err(err!(
+                                InvalidValue,
+                                "Only temperatures can be colored",
+                                "{{{}|color}} is not a temperature",
+                                key
+                            ))
+                        }
+                        (_, false) => out.push_str(&rendered),
+                    }
+                }
+                Part::Condition {
+                    key,
+                    op,
+                    operand,
+                    then,
+                    otherwise,
+                } => {
+                    let value = lookup(status, key)?;
+                    out.push_str(if value.compare(*op, operand) {
+                        then
+                    } else {
+                        otherwise
+                    });
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+impl Part {
+    fn parse(placeholder: &str) -> Result<Self, Error> {
+        if let Some((condition, branches)) = placeholder.split_once('?') {
+            let (then, otherwise) = branches.split_once(':').unwrap_or((branches, ""));
+
+            // the two-character operators first, so `>=` isn't read as `>`
+            let ops = [
+                (">=", Op::Ge),
+                ("<=", Op::Le),
+                ("!=", Op::Ne),
+                (">", Op::Gt),
+                ("<", Op::Lt),
+                ("=", Op::Eq),
+            ];
+            let Some((key, op, operand)) = ops.iter().find_map(|(s, op)| {
+                condition
+                    .split_once(s)
+                    .map(|(key, operand)| (key, *op, operand))
+            }) else {
+                return Err(err!(
+                    InvalidValue,
+                    "Conditions look like {avg>75?HOT} or {level=auto?A:M}",
+                    "{{{}}} has no comparison",
+                    placeholder
+                ));
+            };
+
+            return Ok(Self::Condition {
+                key: key.trim().to_string(),
+                op,
+                operand: operand.trim().to_string(),
+                then: then.to_string(),
+                otherwise: otherwise.to_string(),
+            });
+        }
+
+        let (rest, color) = match placeholder.split_once('|') {
+            Some((rest, "color")) => (rest, true),
+            Some((_, modifier)) => {
+                return Err(err!(
+                    InvalidValue,
+                    "The only modifier is |color",
+                    "Unknown modifier |{}",
+                    modifier
+                ))
+            }
+            None => (placeholder, false),
+        };
+
+        let (key, precision) = match rest.split_once(':') {
+            Some((key, precision)) => {
+                let precision = precision.parse().map_err(|_| {
+                    err!(
+                        InvalidValue,
+                        "The number of decimals goes after the colon, e.g. {avg:0}",
+                        "{} is not a number of decimals",
+                        precision
+                    )
+                })?;
+                (key, Some(precision))
+            }
+            None => (rest, None),
+        };
+
+        Ok(Self::Value {
+            key: key.trim().to_string(),
+            precision,
+            color,
+        })
+    }
+}
+
+impl Value {
+    fn render(&self, precision: Option<usize>) -> String {
+        match self {
+            Self::Temp(temp) => format!("{:.*}", precision.unwrap_or(1), temp.as_celsius()),
+            Self::Number(n) => n.to_string(),
+            Self::Text(s) => s.clone(),
+        }
+    }
+
+    fn as_number(&self) -> Option<f64> {
+        match self {
+            Self::Temp(temp) => Some(temp.as_celsius()),
+            Self::Number(n) => Some(*n as f64),
+            Self::Text(s) => s.parse().ok(),
+        }
+    }
+
+    fn compare(&self, op: Op, operand: &str) -> bool {
+        if let (Some(lhs), Ok(rhs)) = (self.as_number(), operand.parse::<f64>()) {
+            return match op {
+                Op::Gt => lhs > rhs,
+                Op::Ge => lhs >= rhs,
+                Op::Lt => lhs < rhs,
+                Op::Le => lhs <= rhs,
+                Op::Eq => lhs == rhs,
+                Op::Ne => lhs != rhs,
+            };
+        }
+
+        // e.g. `level=auto`, anything but (in)equality makes no sense for text
+        let lhs = self.render(None);
+        match op {
+            Op::Eq => lhs == operand,
+            Op::Ne => lhs != operand,
+            _ => false,
+        }
+    }
+}
+
+fn lookup(status: &Status, key: &str) -> Result<Value, Error> {
+    let missing = || err!(InvalidValue, HELP, "No value for {{{}}}", key);
+
+    let (temps, fan) = (&status.temps, &status.fan);
+
+    let value = match key {
+        "avg" => Value::Temp(temps.avg),
+        "hottest" => Value::Temp(
+            temps
+                .packages
+                .values()
+                .flat_map(|p| p.cores.values().chain(p.dies.values()).map(|c| c.temp))
+                .chain(temps.packages.values().filter_map(|p| p.temp))
+                .max()
+                .unwrap_or(temps.avg),
+        ),
+        "rpm" => Value::Number(fan.speed as u64),
+        "level" => Value::Text(fan.level.to_string()),
+        "enabled" => Value::Text(fan.enabled.to_string()),
+        "watchdog" => match fan.watchdog {
+            Some(secs) => Value::Number(secs as u64),
+            None => Value::Text(String::new()),
+        },
+        "control" => Value::Text(match &status.control {
+            Some(ControlMode::Curve) => "curve".to_string(),
+            Some(ControlMode::Manual { .. }) => "manual".to_string(),
+            None => String::new(),
+        }),
+        key => {
+            if let Some(id) = key.strip_prefix("fan").and_then(|id| id.parse::<u8>().ok()) {
+                let fan = status
+                    .fans
+                    .iter()
+                    .find(|fan| fan.id == id)
+                    .ok_or_else(missing)?;
+                return Ok(Value::Number(fan.rpm as u64));
+            }
+
+            return lookup_sensor(status, key).ok_or_else(missing);
+        }
+    };
+
+    Ok(value)
+}
+
+/// `packageN`, `[packageN/]coreM[.field]` and `[packageN/]dieM[.field]`.
+fn lookup_sensor(status: &Status, key: &str) -> Option<Value> {
+    let temps = &status.temps;
+
+    let (path, field) = key.split_once('.').unwrap_or((key, "temp"));
+    let (package_id, sensor) = match path.split_once('/') {
+        Some((package, sensor)) => (package.strip_prefix("package")?.parse().ok()?, sensor),
+        None => match path.strip_prefix("package") {
+            Some(id) => {
+                let package = temps.packages.get(&id.parse().ok()?)?;
+                return (field == "temp").then_some(Value::Temp(package.temp?));
+            }
+            None => (0, path),
+        },
+    };
+    let package = temps.packages.get(&package_id)?;
+
+    let reading: &CoreTemperature = if let Some(id) = sensor.strip_prefix("core") {
+        package.cores.get(&id.parse().ok()?)?
+    } else if let Some(id) = sensor.strip_prefix("die") {
+        package.dies.get(&id.parse().ok()?)?
+    } else {
+        return None;
+    };
+
+    match field {
+        "temp" => Some(Value::Temp(reading.temp)),
+        "max" => Some(Value::Temp(reading.max)),
+        "critical" => Some(Value::Temp(reading.critical)),
+        "label" => Some(Value::Text(reading.label.clone())),
+        "type" => Some(Value::Text(reading.core_type.to_string())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtpfs::{FanReading, FanSpeed, FanStatus};
+
+    fn status() -> Status {
+        let sensors = r#"{
+            "coretemp-isa-0000": {
+                "Package id 0": { "temp1_input": 61.4, "temp1_max": 100.0, "temp1_crit": 105.0 },
+                "Core 0": { "temp2_input": 55.0, "temp2_max": 100.0, "temp2_crit": 105.0 },
+                "Core 1": { "temp3_input": 70.0, "temp3_max": 100.0, "temp3_crit": 105.0 }
+            }
+        }"#;
+
+        Status {
+            temps: libtpfs::get_temps_from_sensors_output(sensors.to_string()).unwrap(),
+            fan: FanStatus {
+                enabled: true,
+                speed: 2900,
+                level: FanSpeed::Level(3),
+                watchdog: Some(0),
+                commands: Vec::new(),
+            },
+            fans: vec![FanReading { id: 1, rpm: 2900 }],
+            control: None,
+        }
+    }
+
+    fn render(template: &str) -> Result<String, Error> {
+        Template::parse(template)?.render(&status())
+    }
+
+    fn rendered(template: &str) -> String {
+        render(template).unwrap()
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(rendered("{avg}°C {rpm}rpm L{level}"), "61.4°C 2900rpm L3");
+        assert_eq!(
+            rendered("{hottest:0} {core0} {core1.label}"),
+            "70 55.0 Core 1"
+        );
+        assert_eq!(rendered("{package0/core1.max:0} {package0}"), "100 61.4");
+        assert_eq!(
+            rendered("{fan1} {enabled} {watchdog} [{control}]"),
+            "2900 true 0 []"
+        );
+        assert_eq!(rendered("{ avg :2}"), "61.40");
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(rendered("{{avg}} {avg}"), "{avg} 61.4");
+        assert_eq!(rendered("}}{{"), "}{");
+        assert_eq!(rendered("no placeholders"), "no placeholders");
+    }
+
+    #[test]
+    fn unbalanced_braces() {
+        assert!(Template::parse("{avg").is_err());
+        assert!(Template::parse("{avg}{").is_err());
+        assert!(Template::parse("avg}").is_err());
+        assert!(Template::parse("{avg}}").is_err());
+    }
+
+    #[test]
+    fn operators() {
+        for (condition, expected) in [
+            ("avg>61", true),
+            ("avg>61.4", false),
+            ("avg>=61.4", true),
+            ("avg<61.4", false),
+            ("avg<62", true),
+            ("avg<=61.4", true),
+            ("avg=61.4", true),
+            ("avg!=61.4", false),
+            ("level=3", true),
+            ("level!=3", false),
+            ("enabled=true", true),
+            ("enabled!=true", false),
+            // only (in)equality makes sense for text
+            ("enabled>false", false),
+        ] {
+            let expected = if expected { "yes" } else { "no" };
+            assert_eq!(
+                rendered(&format!("{{{condition}?yes:no}}")),
+                expected,
+                "{condition}"
+            );
+        }
+    }
+
+    #[test]
+    fn then_and_else() {
+        assert_eq!(rendered("{avg>75?HOT}"), "");
+        assert_eq!(rendered("{avg<75?ok}"), "ok");
+        assert_eq!(rendered("{level=auto?A:M}"), "M");
+        assert_eq!(rendered("[{level=3?manual 3:}]"), "[manual 3]");
+        assert!(Template::parse("{avg?HOT}").is_err());
+    }
+
+    #[test]
+    fn color() {
+        let template = Template::parse("{avg|color}").unwrap();
+
+        assert!(template.render(&status()).unwrap().contains("\x1b["));
+        assert_eq!(template.render_plain(&status()).unwrap(), "61.4");
+        assert!(render("{rpm|color}").is_err());
+        assert!(Template::parse("{avg|bold}").is_err());
+    }
+
+    #[test]
+    fn bad_placeholders() {
+        assert!(Template::parse("{avg:x}").is_err());
+        assert!(render("{nothing}").is_err());
+        assert!(render("{fan2}").is_err());
+        assert!(render("{core9}").is_err());
+        assert!(render("{core0.nothing}").is_err());
+    }
+}
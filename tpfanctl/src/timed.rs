@@ -0,0 +1,498 @@
+//! `tpfanctl fan <level> --for <duration>` without tpfand: a background holder
+//! puts the previous level back once the time is up.
+//!
+//! The holder arms the thinkpad_acpi watchdog and keeps re-arming it, so if it
+//! is killed (even with `SIGKILL`) the firmware goes back to `auto` by itself
+//! within [`WATCHDOG`] seconds. What it holds, and until when, is kept in a
+//! state file ([`STATE_PATH`], see [`Overrides`]) for `tpfanctl fan` to show.
+//! With tpfand, the daemon does all of this instead, see
+//! [`libtpfs::ipc::Request::SetLevel`].
+
+use libtpfanspeed as libtpfs;
+use libtpfs::{
+    backend::{FanBackend, ProcfsBackend},
+    err,
+    error::Error,
+    generic_err, FanSpeed,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const STATE_PATH: &str = "/run/tpfanctl-timed.json";
+
+/// The watchdog timeout while a level is held.
+pub const WATCHDOG: u8 = 30;
+
+/// How often the holder re-arms the watchdog, well within [`WATCHDOG`].
+const KEEPALIVE: Duration = Duration::from_secs(10);
+
+/// A level set for a while, as saved in the state file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimedOverride {
+    /// The holder's pid.
+    pub pid: u32,
+    pub level: FanSpeed,
+    /// The level to put back.
+    #[serde(deserialize_with = "FanSpeed::deserialize_reading")]
+    pub previous: FanSpeed,
+    /// The watchdog timeout to put back, 0 for off.
+    pub watchdog: u8,
+    /// When to put `previous` back, in seconds since the Unix epoch.
+    pub until: u64,
+}
+
+impl TimedOverride {
+    /// How long until `previous` is put back.
+    pub fn remaining(&self) -> Duration {
+        Duration::from_secs(self.until.saturating_sub(now()))
+    }
+}
+
+/// The timed overrides of a fan, kept in a state file.
+#[derive(Debug, Clone)]
+pub struct Overrides<B: FanBackend + Clone + Send + 'static = ProcfsBackend> {
+    state: PathBuf,
+    backend: B,
+}
+
+impl Overrides {
+    /// For `/proc/acpi/ibm/fan`, kept in [`STATE_PATH`].
+    pub fn new() -> Self {
+        Self::with_backend(STATE_PATH, ProcfsBackend::default())
+    }
+}
+
+impl Default for Overrides {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: FanBackend + Clone + Send + 'static> Overrides<B> {
+    pub fn with_backend(state: impl Into<PathBuf>, backend: B) -> Self {
+        Self {
+            state: state.into(),
+            backend,
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// The override in the state file, if its holder is still running.
+    pub fn active(&self) -> Option<TimedOverride> {
+        let state: TimedOverride =
+            serde_json::from_str(&fs::read_to_string(&self.state).ok()?).ok()?;
+
+        Path::new(&format!("/proc/{}", state.pid))
+            .exists()
+            .then_some(state)
+    }
+
+    /// Whether `state` is still the active override, and not one that replaced it.
+    fn is_current(&self, state: &TimedOverride) -> bool {
+        self.active().is_some_and(|active| active.pid == state.pid)
+    }
+
+    fn save(&self, state: &TimedOverride) -> Result<(), Error> {
+        let json = serde_json::to_string(state).map_err(|e| generic_err!(e))?;
+        fs::write(&self.state, json).map_err(|e| self.state_err(e))
+    }
+
+    /// Remove the state file, unless another override replaced `state` already.
+    fn remove(&self, state: &TimedOverride) -> Result<(), Error> {
+        if !self.is_current(state) {
+            return Ok(());
+        }
+
+        match fs::remove_file(&self.state) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(self.state_err(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Put the previous level and watchdog back, and forget the override.
+    fn restore(&mut self, state: &TimedOverride) -> Result<(), Error> {
+        self.remove(state)?;
+        self.backend.set_level(state.previous)?;
+        self.backend.set_watchdog(state.watchdog)
+    }
+
+    fn state_err(&self, e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => err!(
+                PermissionDenied,
+                "Do you have sufficient permissions?",
+                "while trying to write to {}",
+                self.state.display()
+            )
+            .with_source(e),
+            _ => generic_err!(e),
+        }
+    }
+
+    /// Set `level` now, and start a holder (`tpfanctl fan hold`) that puts
+    /// the current level back after `duration`. Replaces any override that is
+    /// already running, but keeps the level from before it.
+    pub fn start(&mut self, level: FanSpeed, duration: Duration) -> Result<TimedOverride, Error> {
+        let state = self.state.clone();
+
+        self.start_with(level, duration, |holder| {
+            let exe = std::env::current_exe()?;
+            Command::new(exe)
+                .args(["fan", "hold"])
+                .args(["--level", &holder.level.to_string()])
+                .args(["--previous", &holder.previous.to_string()])
+                .args(["--watchdog", &holder.watchdog.to_string()])
+                .args(["--until", &holder.until.to_string()])
+                .arg("--state")
+                .arg(&state)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                // out of the terminal's process group, so ^C on it doesn't reach the holder
+                .process_group(0)
+                .spawn()
+        })
+    }
+
+    /// [`Overrides::start`], with `spawn` starting the holder for the override
+    /// it is given (whose `pid` is still 0).
+    fn start_with(
+        &mut self,
+        level: FanSpeed,
+        duration: Duration,
+        spawn: impl FnOnce(&TimedOverride) -> io::Result<Child>,
+    ) -> Result<TimedOverride, Error> {
+        let status = self.backend.status()?;
+
+        if !status.supports("watchdog") {
+            return Err(err!(
+                Unsupported,
+                "Run tpfand, which doesn't need it",
+                "This fan has no watchdog to fall back on"
+            ));
+        }
+
+        let (previous, watchdog) = match self.active() {
+            Some(active) => (active.previous, active.watchdog),
+            None => (status.level, status.watchdog.unwrap_or(0)),
+        };
+        let mut state = TimedOverride {
+            pid: 0,
+            level,
+            previous,
+            watchdog,
+            // rounded up, so it's never shorter than asked for
+            until: now() + duration.as_secs() + u64::from(duration.subsec_nanos() > 0),
+        };
+
+        let mut holder = spawn(&state).map_err(|e| generic_err!(e))?;
+        state.pid = holder.id();
+
+        // saved first, so a holder this one replaces stops before the level changes
+        let res = self
+            .save(&state)
+            .and_then(|_| self.backend.set_level(level))
+            .and_then(|_| self.backend.set_watchdog(WATCHDOG));
+
+        if let Err(e) = res {
+            let _ = holder.kill();
+            let _ = self.remove(&state);
+            return Err(e);
+        }
+
+        Ok(state)
+    }
+
+    /// Stop the running override, if any, leaving the level as it is. For
+    /// anything that sets the fan by other means.
+    pub fn cancel(&mut self) -> Result<(), Error> {
+        match self.active() {
+            Some(active) => {
+                self.remove(&active)?;
+                self.backend.set_watchdog(active.watchdog)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The holder started by [`Overrides::start`], as the hidden `tpfanctl fan hold`.
+    ///
+    /// Re-arms the watchdog until `until`, then puts `previous` back. Stops
+    /// early, leaving the fan alone, if the level is changed by something else
+    /// or another override replaces this one.
+    pub fn hold(
+        &mut self,
+        level: FanSpeed,
+        previous: FanSpeed,
+        watchdog: u8,
+        until: u64,
+    ) -> Result<(), Error> {
+        let state = TimedOverride {
+            pid: std::process::id(),
+            level,
+            previous,
+            watchdog,
+            until,
+        };
+
+        let (mut on_signal, signal_state) = (self.clone(), state.clone());
+        ctrlc::set_handler(move || {
+            if on_signal.is_current(&signal_state) {
+                let _ = on_signal.restore(&signal_state);
+            }
+            std::process::exit(130);
+        })
+        .map_err(|e| generic_err!(e))?;
+
+        loop {
+            thread::sleep(state.remaining().min(KEEPALIVE));
+
+            if !self.is_current(&state) {
+                return Ok(());
+            }
+
+            if state.remaining().is_zero() {
+                return self.restore(&state);
+            }
+
+            if !self.backend.status()?.level.is_same_as(level) {
+                self.remove(&state)?;
+                return self.backend.set_watchdog(watchdog);
+            }
+
+            self.backend.set_watchdog(WATCHDOG)?;
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A duration like `90`, `90s`, `10m`, `1h30m`, `1h 30m` or `1d`. Plain numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let invalid = || {
+        err!(
+            InvalidValue,
+            "Durations look like 90s, 10m or 1h30m",
+            "{} is an invalid duration",
+            s
+        )
+    };
+
+    if s.trim().is_empty() {
+        return Err(invalid());
+    }
+
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        // between parts, like in `format_duration`, but not inside one
+        if c.is_whitespace() && number.is_empty() {
+            continue;
+        }
+
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let n: u64 = number.parse().map_err(|_| invalid())?;
+        secs = n
+            .checked_mul(unit)
+            .and_then(|n| secs.checked_add(n))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+
+    if !number.is_empty() {
+        secs = secs
+            .checked_add(number.parse().map_err(|_| invalid())?)
+            .ok_or_else(invalid)?;
+    }
+
+    if secs == 0 {
+        return Err(err!(
+            ValueTooLow,
+            "Durations look like 90s, 10m or 1h30m",
+            "{} is too short, it has to be at least a second",
+            s
+        ));
+    }
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// e.g. `1h 5m`, `10m`, `9m 12s` or `30s`.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, 0) => format!("{m}m"),
+        (0, m, s) => format!("{m}m {s}s"),
+        (h, 0, _) => format!("{h}h"),
+        (h, m, _) => format!("{h}h {m}m"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtpfs::{backend::MockBackend, error::ErrorKind};
+
+    fn secs(s: &str) -> u64 {
+        parse_duration(s).unwrap().as_secs()
+    }
+
+    fn kind(s: &str) -> ErrorKind {
+        *parse_duration(s).unwrap_err().kind()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(secs("90"), 90);
+        assert_eq!(secs("90s"), 90);
+        assert_eq!(secs("10m"), 600);
+        assert_eq!(secs("1h30m"), 5400);
+        assert_eq!(secs(" 1h 30m "), 5400);
+        assert_eq!(secs("1d"), 86400);
+        assert_eq!(secs("1m30"), 90);
+
+        assert_eq!(kind("0"), ErrorKind::ValueTooLow);
+        assert_eq!(kind("0m0s"), ErrorKind::ValueTooLow);
+        for invalid in ["abc", "10x", "m", "1hm", "1 0m", "-5", ""] {
+            assert_eq!(kind(invalid), ErrorKind::InvalidValue, "{invalid:?}");
+        }
+        for overflowing in [
+            "18446744073709551616",
+            "18446744073709551615d",
+            "18446744073709551615s1s",
+        ] {
+            assert_eq!(kind(overflowing), ErrorKind::InvalidValue, "{overflowing}");
+        }
+    }
+
+    #[test]
+    fn format_and_parse_again() {
+        for (secs, formatted) in [
+            (30, "30s"),
+            (600, "10m"),
+            (552, "9m 12s"),
+            (3600, "1h"),
+            (3900, "1h 5m"),
+        ] {
+            let d = Duration::from_secs(secs);
+            assert_eq!(format_duration(d), formatted);
+            assert_eq!(parse_duration(formatted).unwrap(), d);
+        }
+
+        // hours leave out the seconds
+        assert_eq!(format_duration(Duration::from_secs(3959)), "1h 5m");
+    }
+
+    /// Overrides kept in a directory of their own, on a fan at `auto`.
+    fn overrides(test: &str) -> Overrides<MockBackend> {
+        let dir = std::env::temp_dir().join(format!("tpfanctl-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        Overrides::with_backend(dir.join("timed.json"), MockBackend::new())
+    }
+
+    /// A stand-in for the holder, which is stopped by the test itself.
+    fn holder(_: &TimedOverride) -> io::Result<Child> {
+        Command::new("sleep").arg("60").spawn()
+    }
+
+    #[test]
+    fn start_and_cancel() {
+        let mut overrides = overrides("start-and-cancel");
+        assert_eq!(overrides.active(), None);
+
+        let state = overrides
+            .start_with(FanSpeed::Level(7), Duration::from_secs(60), holder)
+            .unwrap();
+        assert_eq!(state.previous, FanSpeed::Auto);
+        assert_eq!(state.watchdog, 0);
+        assert!(state.remaining() > Duration::from_secs(58));
+        assert_eq!(overrides.active(), Some(state.clone()));
+
+        let mut backend = overrides.backend().clone();
+        assert_eq!(backend.status().unwrap().level, FanSpeed::Level(7));
+        assert_eq!(backend.status().unwrap().watchdog, Some(WATCHDOG));
+
+        // another one keeps the level from before the first
+        let replaced = state;
+        let state = overrides
+            .start_with(FanSpeed::Level(5), Duration::from_secs(60), holder)
+            .unwrap();
+        assert_eq!(state.previous, FanSpeed::Auto);
+        assert_eq!(overrides.active(), Some(state.clone()));
+
+        overrides.cancel().unwrap();
+        assert_eq!(overrides.active(), None);
+        let mut backend = overrides.backend().clone();
+        assert_eq!(backend.status().unwrap().level, FanSpeed::Level(5));
+        assert_eq!(backend.status().unwrap().watchdog, Some(0));
+
+        for pid in [replaced.pid, state.pid] {
+            let _ = Command::new("kill").arg(pid.to_string()).status();
+        }
+    }
+
+    #[test]
+    fn a_dead_holder_is_no_override() {
+        let mut overrides = overrides("dead-holder");
+
+        overrides
+            .start_with(FanSpeed::Level(7), Duration::from_secs(60), |_| {
+                let mut holder = Command::new("true").spawn()?;
+                // reaped, so its pid is gone from /proc
+                holder.wait()?;
+                Ok(holder)
+            })
+            .unwrap();
+
+        assert_eq!(overrides.active(), None);
+        // nothing to cancel, the watchdog is left to the dead holder's fallback
+        overrides.cancel().unwrap();
+    }
+
+    #[test]
+    fn no_watchdog_no_override() {
+        let mut status = MockBackend::new().status().unwrap();
+        status.commands.retain(|c| !c.starts_with("watchdog"));
+        let mut overrides = overrides("no-watchdog");
+        overrides.backend = MockBackend::with_status(status);
+
+        let e = overrides
+            .start_with(FanSpeed::Level(7), Duration::from_secs(60), |_| {
+                panic!("no holder without a watchdog")
+            })
+            .unwrap_err();
+        assert_eq!(e.kind(), &ErrorKind::Unsupported);
+        assert!(overrides.backend().writes().is_empty());
+    }
+}
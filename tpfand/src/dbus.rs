@@ -0,0 +1,274 @@
+//! Serves the fan on D-Bus, as `io.github.ezntek.tpfand.Fan1` at [`OBJECT_PATH`].
+//!
+//! Properties come from the daemon's [`Snapshot`]s and emit `PropertiesChanged`
+//! when they change. Methods are forwarded to the daemon loop like socket
+//! [`Request`]s, so both behave the same.
+
+use crate::{Event, Snapshot};
+use libtpfanspeed as libtpfs;
+use libtpfs::{
+    error::*,
+    ipc::{Request, Response},
+    FanSpeed,
+};
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+use zbus::{blocking::connection, fdo, interface};
+
+pub const BUS_NAME: &str = "io.github.ezntek.tpfand";
+pub const OBJECT_PATH: &str = "/io/github/ezntek/tpfand";
+
+/// The property values of one [`Snapshot`].
+#[derive(Debug, Clone, PartialEq)]
+struct Properties {
+    level: String,
+    rpm: u16,
+    control_mode: String,
+    profile: String,
+    failsafe: String,
+    temperature: f64,
+    temperatures: HashMap<String, f64>,
+}
+
+impl From<&Snapshot> for Properties {
+    fn from(snapshot: &Snapshot) -> Self {
+        let mut temperatures = HashMap::new();
+        if let Some(temps) = &snapshot.temps {
+            for (package_id, package) in &temps.packages {
+                let package_key = format!("package{package_id}");
+
+                if let Some(temp) = package.temp {
+                    temperatures.insert(package_key.clone(), temp.as_celsius());
+                }
+                for (core_id, core) in &package.cores {
+                    temperatures.insert(
+                        format!("{package_key}/core{core_id}"),
+                        core.temp.as_celsius(),
+                    );
+                }
+                for (die_id, die) in &package.dies {
+                    temperatures
+                        .insert(format!("{package_key}/ccd{die_id}"), die.temp.as_celsius());
+                }
+            }
+        }
+
+        Self {
+            level: snapshot.status.fan.level.to_string(),
+            rpm: snapshot.status.fan.speed,
+            control_mode: match snapshot.status.control {
+                libtpfs::ipc::ControlMode::Curve => "curve".to_string(),
+                libtpfs::ipc::ControlMode::Manual { .. } => "manual".to_string(),
+            },
+            profile: snapshot.status.profile.clone().unwrap_or_default(),
+            failsafe: snapshot
+                .status
+                .failsafe
+                .as_ref()
+                .map(|trip| trip.to_string())
+                .unwrap_or_default(),
+            temperature: snapshot
+                .temps
+                .as_ref()
+                .map_or(f64::NAN, |temps| temps.avg.as_celsius()),
+            temperatures,
+        }
+    }
+}
+
+struct FanInterface {
+    events: Sender<Event>,
+    properties: Option<Properties>,
+}
+
+impl FanInterface {
+    fn properties(&self) -> fdo::Result<&Properties> {
+        self.properties
+            .as_ref()
+            .ok_or_else(|| fdo::Error::Failed("No readings yet".to_string()))
+    }
+
+    fn request(&self, request: Request) -> fdo::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let response = self
+            .events
+            .send(Event::Request(request, tx))
+            .ok()
+            .and_then(|_| rx.recv().ok());
+
+        match response {
+            Some(Response::Error(e)) => Err(fdo::Error::Failed(e.to_string())),
+            Some(_) => Ok(()),
+            None => Err(fdo::Error::Failed("tpfand is shutting down".to_string())),
+        }
+    }
+}
+
+#[interface(name = "io.github.ezntek.tpfand.Fan1")]
+impl FanInterface {
+    /// The fan level: 0-7, auto, full-speed or disengaged.
+    #[zbus(property)]
+    fn level(&self) -> fdo::Result<String> {
+        Ok(self.properties()?.level.clone())
+    }
+
+    #[zbus(property)]
+    fn rpm(&self) -> fdo::Result<u16> {
+        Ok(self.properties()?.rpm)
+    }
+
+    /// `curve`, or `manual` after SetLevel.
+    #[zbus(property)]
+    fn control_mode(&self) -> fdo::Result<String> {
+        Ok(self.properties()?.control_mode.clone())
+    }
+
+    /// The profile followed instead of the curve, empty if none.
+    #[zbus(property)]
+    fn profile(&self) -> fdo::Result<String> {
+        Ok(self.properties()?.profile.clone())
+    }
+
+    /// Why the overheat failsafe overrides the level, empty if it doesn't.
+    #[zbus(property)]
+    fn failsafe(&self) -> fdo::Result<String> {
+        Ok(self.properties()?.failsafe.clone())
+    }
+
+    /// The average CPU temperature in °C, NaN if it can't be read.
+    #[zbus(property)]
+    fn temperature(&self) -> fdo::Result<f64> {
+        Ok(self.properties()?.temperature)
+    }
+
+    /// CPU temperatures in °C, keyed like `package0`, `package0/core3` or `package0/ccd1`.
+    #[zbus(property)]
+    fn temperatures(&self) -> fdo::Result<HashMap<String, f64>> {
+        Ok(self.properties()?.temperatures.clone())
+    }
+
+    /// Override the curve with a fixed level, until Resume.
+    fn set_level(&self, level: &str) -> fdo::Result<()> {
+        let level = FanSpeed::from_string(level.to_string())
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+
+        self.request(Request::SetLevel {
+            level,
+            fan: None,
+            duration: None,
+        })
+    }
+
+    /// Override the curve with a fixed level, for `secs` seconds or until Resume.
+    fn set_level_for(&self, level: &str, secs: u64) -> fdo::Result<()> {
+        let level = FanSpeed::from_string(level.to_string())
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+
+        self.request(Request::SetLevel {
+            level,
+            fan: None,
+            duration: Some(secs),
+        })
+    }
+
+    /// Go back to the curve.
+    fn resume(&self) -> fdo::Result<()> {
+        self.request(Request::Resume)
+    }
+
+    /// Follow a profile instead of the curve, also after a restart.
+    fn set_profile(&self, name: &str) -> fdo::Result<()> {
+        self.request(Request::SetProfile {
+            name: name.to_string(),
+        })
+    }
+}
+
+/// Claim [`BUS_NAME`] on `bus` (see [`libtpfs::config::Config::dbus`]) and keep
+/// the properties up to date from `snapshots` in a background thread.
+///
+/// The service goes away with the returned connection.
+pub fn spawn(
+    bus: &str,
+    events: Sender<Event>,
+    snapshots: Receiver<Snapshot>,
+) -> Result<zbus::blocking::Connection, Error> {
+    let iface = FanInterface {
+        events,
+        properties: None,
+    };
+
+    let conn = match bus {
+        "system" => connection::Builder::system(),
+        "session" => connection::Builder::session(),
+        address => connection::Builder::address(address),
+    }
+    .and_then(|builder| {
+        builder
+            .name(BUS_NAME)?
+            .serve_at(OBJECT_PATH, iface)?
+            .build()
+    })
+    .map_err(|e| {
+        err!(
+            Ipc,
+            "Is the tpfand D-Bus policy installed? See tpfand/io.github.ezntek.tpfand.conf",
+            "Can't serve {} on the {} bus: {}",
+            BUS_NAME,
+            bus,
+            e
+        )
+    })?;
+
+    let iface_ref = conn
+        .object_server()
+        .interface::<_, FanInterface>(OBJECT_PATH)
+        .map_err(|e| generic_err!(e))?;
+
+    thread::spawn(move || {
+        for snapshot in snapshots {
+            let new = Properties::from(&snapshot);
+            let mut iface = iface_ref.get_mut();
+            let old = iface.properties.replace(new.clone());
+            let emitter = iface_ref.signal_emitter();
+
+            let res = async_io::block_on(async {
+                let old = old.as_ref();
+
+                if old.is_none_or(|old| old.level != new.level) {
+                    iface.level_changed(emitter).await?;
+                }
+                if old.is_none_or(|old| old.rpm != new.rpm) {
+                    iface.rpm_changed(emitter).await?;
+                }
+                if old.is_none_or(|old| old.control_mode != new.control_mode) {
+                    iface.control_mode_changed(emitter).await?;
+                }
+                if old.is_none_or(|old| old.profile != new.profile) {
+                    iface.profile_changed(emitter).await?;
+                }
+                if old.is_none_or(|old| old.failsafe != new.failsafe) {
+                    iface.failsafe_changed(emitter).await?;
+                }
+                // NaN while the temperatures can't be read, which is never equal to itself
+                if old.is_none_or(|old| old.temperature.to_bits() != new.temperature.to_bits()) {
+                    iface.temperature_changed(emitter).await?;
+                }
+                if old.is_none_or(|old| old.temperatures != new.temperatures) {
+                    iface.temperatures_changed(emitter).await?;
+                }
+
+                zbus::Result::Ok(())
+            });
+
+            if let Err(e) = res {
+                eprintln!("D-Bus: {e}");
+            }
+        }
+    });
+
+    Ok(conn)
+}
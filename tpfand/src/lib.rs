@@ -0,0 +1,653 @@
+//! tpfand: sets the fan level from a [`FanCurve`] over the temperatures in its [`Config`]
+//! (or from a [`Profile`]), unless the [`Failsafe`] overrides it.
+//!
+//! [`FanCurve`]: libtpfs::curve::FanCurve
+
+pub mod dbus;
+pub mod metrics;
+pub mod server;
+
+use libtpfanspeed as libtpfs;
+use libtpfs::{
+    backend::FanBackend,
+    config::Config,
+    error::*,
+    failsafe::Failsafe,
+    ipc::{ControlMode, DaemonStatus, Request, Response},
+    profile::Profile,
+    FanReading, FanSpeed, FanStatus, MilliCelsius, Temperatures,
+};
+use std::{
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+/// Something the daemon has to react to between two readings.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Restore `auto` and stop.
+    Shutdown,
+    /// A client request, answered on the sender.
+    Request(Request, Sender<Response>),
+}
+
+/// What the daemon saw after a reading or a change of level, see [`Daemon::subscribe`].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub status: DaemonStatus,
+    /// `None` if the temperatures couldn't be read.
+    pub temps: Option<Temperatures>,
+    /// Empty if the fans couldn't be read.
+    pub fans: Vec<FanReading>,
+}
+
+pub struct Daemon<B: FanBackend> {
+    config: Config,
+    backend: B,
+    /// The last level written, `None` until the first one.
+    level: Option<FanSpeed>,
+    control: ControlMode,
+    /// When a timed [`Request::SetLevel`] is over, and what was in control before it.
+    expires: Option<(Instant, ControlMode)>,
+    /// Followed instead of the curve from the config.
+    profile: Option<(String, Profile)>,
+    /// The last temperature read, if it could be.
+    temp: Option<MilliCelsius>,
+    /// The CPU temperatures of the last reading, for [`Snapshot`]s.
+    temps: Option<Temperatures>,
+    /// Whether the last reading failed, so the failure is only logged once.
+    failing: bool,
+    failsafe: Failsafe,
+    subscribers: Vec<Sender<Snapshot>>,
+}
+
+impl<B: FanBackend> Daemon<B> {
+    pub fn new(config: Config, backend: B) -> Self {
+        Self {
+            failsafe: Failsafe::new(config.overheat),
+            config,
+            backend,
+            level: None,
+            control: ControlMode::Curve,
+            expires: None,
+            profile: None,
+            temp: None,
+            temps: None,
+            failing: false,
+            subscribers: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn level(&self) -> Option<FanSpeed> {
+        self.level
+    }
+
+    pub fn control(&self) -> ControlMode {
+        self.control
+    }
+
+    pub fn failsafe(&self) -> &Failsafe {
+        &self.failsafe
+    }
+
+    /// When the current override is over, if it was set for a while.
+    pub fn expires(&self) -> Option<Instant> {
+        self.expires.map(|(expires, _)| expires)
+    }
+
+    /// The name of the profile followed instead of the curve, if any.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_ref().map(|(name, _)| name.as_str())
+    }
+
+    /// Follow the profile `name` instead of the curve, dropping any override.
+    /// Takes effect on the next [`Daemon::tick`].
+    pub fn set_profile(&mut self, name: &str) -> Result<(), Error> {
+        let profile = self.config.profile(name)?;
+
+        self.profile = Some((name.to_string(), profile));
+        self.control = ControlMode::Curve;
+        self.expires = None;
+        Ok(())
+    }
+
+    fn status(&self, fan: FanStatus) -> DaemonStatus {
+        DaemonStatus {
+            fan,
+            control: self.control,
+            temp: self.temp,
+            failsafe: self.failsafe.trip().cloned(),
+            expires_in: self.expires().map(|expires| {
+                // rounded up, so it only says 0 once it's over
+                let left = expires.saturating_duration_since(Instant::now());
+                left.as_secs() + u64::from(left.subsec_nanos() > 0)
+            }),
+            after_expiry: self.expires.map(|(_, previous)| previous),
+            profile: self.profile().map(str::to_string),
+        }
+    }
+
+    /// Get a [`Snapshot`] after every reading and every level set by a client.
+    pub fn subscribe(&mut self) -> Receiver<Snapshot> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Send the temperatures of the last reading, and the fan as it is now,
+    /// which ticks don't read back.
+    fn publish(&mut self) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+
+        let fan = match self.backend.status() {
+            Ok(fan) => fan,
+            Err(e) => {
+                eprintln!("{e}");
+                return;
+            }
+        };
+
+        let snapshot = Snapshot {
+            status: self.status(fan),
+            temps: self.temps.clone(),
+            fans: self.backend.fans().unwrap_or_default(),
+        };
+
+        self.subscribers
+            .retain(|subscriber| subscriber.send(snapshot.clone()).is_ok());
+    }
+
+    /// The temperature the curve is evaluated against: the hottest sensor in
+    /// the config, or the average core temperature if there are none.
+    pub fn read_temp(&self) -> Result<MilliCelsius, Error> {
+        let source = self.config.temp_source;
+
+        if self.config.sensors.is_empty() {
+            return Ok(libtpfs::get_temps_from(source)?.avg);
+        }
+
+        libtpfs::get_sensor_readings_from(source)?
+            .into_iter()
+            .filter(|reading| self.config.sensors.iter().any(|s| s.matches(reading)))
+            .map(|reading| reading.temp)
+            .max()
+            .ok_or_else(|| {
+                err!(
+                    NoReadings,
+                    "Check the sensors in the config against `tpfanctl temp --all`",
+                    "None of the configured sensors could be read"
+                )
+            })
+    }
+
+    /// Keep `temps` for the next [`Snapshot`], and take the temperature the
+    /// curve is evaluated against from them, unless the config names sensors
+    /// (see [`Daemon::read_temp`]).
+    pub fn take_reading(
+        &mut self,
+        temps: Result<Temperatures, Error>,
+    ) -> Result<MilliCelsius, Error> {
+        let temp = match &temps {
+            _ if !self.config.sensors.is_empty() => self.read_temp(),
+            Ok(temps) => Ok(temps.avg),
+            Err(e) => Err(e.clone()),
+        };
+
+        self.temps = temps.ok();
+        temp
+    }
+
+    /// Check every core against the overheat policy, see [`Failsafe::update`].
+    /// Takes effect on the next [`Daemon::tick`].
+    pub fn check_overheat(&mut self, temps: &Temperatures) {
+        let was_tripped = self.failsafe.trip().is_some();
+        let level = self.failsafe.policy().level();
+
+        match (was_tripped, self.failsafe.update(temps)) {
+            (false, Some(trip)) => eprintln!("Overheating: {trip}, forcing {level}"),
+            (true, None) => eprintln!("Cooled down, releasing the overheat failsafe"),
+            _ => (),
+        }
+    }
+
+    /// Apply the level for one reading, or the failsafe level if it failed.
+    /// The level is only written when it changes. While overridden through
+    /// [`Request::SetLevel`], that level is kept instead of the curve's (or the
+    /// profile's). The overheat failsafe beats both.
+    pub fn tick(&mut self, temp: Result<MilliCelsius, Error>) -> Result<FanSpeed, Error> {
+        self.temp = temp.as_ref().ok().copied();
+
+        let next = match (self.control, &self.profile) {
+            (ControlMode::Manual { level }, _) => level,
+            (ControlMode::Curve, Some((_, Profile::Level(level)))) => *level,
+            (ControlMode::Curve, _) => self.curve_level(temp),
+        };
+        let next = self.failsafe.apply(next);
+
+        if self.level != Some(next) {
+            self.backend.set_level(next)?;
+            eprintln!("Fan level set to {next}");
+            self.level = Some(next);
+        }
+
+        Ok(next)
+    }
+
+    fn curve_level(&mut self, temp: Result<MilliCelsius, Error>) -> FanSpeed {
+        match temp {
+            Ok(temp) => {
+                if self.failing {
+                    eprintln!("Temperature readings are back ({temp}°C)");
+                    self.failing = false;
+                }
+
+                let curve = match &self.profile {
+                    Some((_, Profile::Curve(curve))) => curve,
+                    _ => &self.config.curve,
+                };
+                curve.evaluate(temp, self.level)
+            }
+            Err(e) => {
+                if !self.failing {
+                    eprintln!("{e}, using the failsafe level {}", self.config.failsafe);
+                    self.failing = true;
+                }
+
+                self.config.failsafe
+            }
+        }
+    }
+
+    /// Answer a client request.
+    pub fn handle(&mut self, request: Request) -> Response {
+        let source = self.config.temp_source;
+
+        let res = match request {
+            Request::Status => self
+                .backend
+                .status()
+                .map(|fan| Response::Status(self.status(fan))),
+            Request::Temps => libtpfs::get_temps_from(source).map(Response::Temps),
+            Request::Sensors => libtpfs::get_sensor_readings_from(source).map(Response::Sensors),
+            Request::Fans => self.backend.fans().map(Response::Fans),
+            // kept for after the failsafe releases, see `tick`
+            Request::SetLevel {
+                level, duration, ..
+            } if self.failsafe.trip().is_some() => {
+                eprintln!("Fan level overridden to {level}, once cooled down");
+                self.override_level(level, duration);
+                self.publish();
+                Ok(Response::Ok)
+            }
+            Request::SetLevel {
+                level,
+                fan,
+                duration,
+            } => match fan {
+                Some(fan) => self.backend.set_fan_level(fan, level),
+                None => self.backend.set_level(level),
+            }
+            .map(|_| {
+                match duration {
+                    Some(secs) => eprintln!("Fan level overridden to {level} for {secs}s"),
+                    None => eprintln!("Fan level overridden to {level}"),
+                }
+                self.level = Some(level);
+                self.override_level(level, duration);
+                self.publish();
+                Response::Ok
+            }),
+            Request::Resume => {
+                eprintln!("Back to the curve");
+                self.control = ControlMode::Curve;
+                self.expires = None;
+                let temp = self.take_reading(libtpfs::get_temps_from(source));
+                let res = self.tick(temp).map(|_| Response::Ok);
+                self.publish();
+                res
+            }
+            Request::Profiles => Ok(Response::Profiles(self.config.all_profiles())),
+            // saved first, so it is only used if it will be again after a reboot
+            Request::SetProfile { name } => self
+                .config
+                .profile(&name)
+                .and_then(|_| libtpfs::profile::save_active(&self.config.profile_state, &name))
+                .and_then(|_| self.set_profile(&name))
+                .and_then(|_| {
+                    eprintln!("Switched to the {name} profile");
+                    let temp = self.take_reading(libtpfs::get_temps_from(source));
+                    let res = self.tick(temp).map(|_| Response::Ok);
+                    self.publish();
+                    res
+                }),
+        };
+
+        res.unwrap_or_else(Response::Error)
+    }
+
+    /// Keep `level` instead of the curve, for `duration` seconds or until
+    /// [`Request::Resume`]. A timed override goes back to what was in control
+    /// before it, and one replacing another to what was before that. One
+    /// without a duration drops the profile, which no longer decides the level.
+    fn override_level(&mut self, level: FanSpeed, duration: Option<u64>) {
+        let previous = match self.expires {
+            Some((_, previous)) => previous,
+            None => self.control,
+        };
+
+        if let Some((name, _)) = self.profile.take_if(|_| duration.is_none()) {
+            eprintln!("Leaving the {name} profile");
+            if let Err(e) = libtpfs::profile::clear_active(&self.config.profile_state) {
+                eprintln!("{e}");
+            }
+        }
+
+        self.control = ControlMode::Manual { level };
+        self.expires = duration.map(|secs| (Instant::now() + Duration::from_secs(secs), previous));
+    }
+
+    /// Go back to what was in control before a timed override, once it is over.
+    /// Takes effect on the next [`Daemon::tick`].
+    pub fn expire_override(&mut self) {
+        let Some((expires, previous)) = self.expires else {
+            return;
+        };
+
+        if expires <= Instant::now() {
+            match previous {
+                ControlMode::Curve => eprintln!("Override expired, back to the curve"),
+                ControlMode::Manual { level } => {
+                    eprintln!("Override expired, back to level {level}")
+                }
+            }
+            self.control = previous;
+            self.expires = None;
+        }
+    }
+
+    /// Hand the fan back to the firmware.
+    pub fn restore(&mut self) -> Result<(), Error> {
+        self.backend.set_level(FanSpeed::Auto)?;
+        self.level = Some(FanSpeed::Auto);
+        Ok(())
+    }
+
+    /// Poll every [`Config::interval`] (and when a timed override ends) and answer
+    /// requests in between, until [`Event::Shutdown`] is received (or every
+    /// sender is gone), then restore `auto`.
+    pub fn run(&mut self, events: &Receiver<Event>) -> Result<(), Error> {
+        if !self.backend.status()?.is_controllable() {
+            return Err(err!(
+                FanControlDisabled,
+                "Did you load thinkpad_acpi with fan_control=1?",
+                "Can't control the fan speed"
+            ));
+        }
+
+        loop {
+            self.expire_override();
+
+            let temps = libtpfs::get_temps_from(self.config.temp_source);
+            if let Ok(temps) = &temps {
+                self.check_overheat(temps);
+            }
+
+            let temp = self.take_reading(temps);
+            if let Err(e) = self.tick(temp) {
+                // try again on the next reading
+                eprintln!("{e}");
+            }
+            self.publish();
+
+            let next_tick = Instant::now() + self.config.interval();
+            loop {
+                // wake up early for an override that ends before the next reading
+                let wake = self
+                    .expires()
+                    .map_or(next_tick, |expires| expires.min(next_tick));
+                let timeout = wake.saturating_duration_since(Instant::now());
+                match events.recv_timeout(timeout) {
+                    Ok(Event::Request(request, reply)) => {
+                        let _ = reply.send(self.handle(request));
+                    }
+                    Ok(Event::Shutdown) | Err(RecvTimeoutError::Disconnected) => {
+                        eprintln!("Shutting down, restoring auto");
+                        return self.restore();
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtpfs::backend::MockBackend;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// auto, 3 from 60°C (until below 55°C), 7 from 75°C (until below 70°C),
+    /// keeping its profile in a directory of its own.
+    fn daemon() -> Daemon<MockBackend> {
+        static DAEMONS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut config = Config::from_toml(
+            r#"
+            failsafe = "full-speed"
+
+            [curve]
+            base = "auto"
+            points = [
+                { speed = 3, rising = 60, falling = 55 },
+                { speed = 7, rising = 75, falling = 70 },
+            ]
+            "#,
+        )
+        .unwrap();
+        config.profile_state = std::env::temp_dir()
+            .join(format!(
+                "tpfand-{}-{}",
+                std::process::id(),
+                DAEMONS.fetch_add(1, Ordering::SeqCst)
+            ))
+            .join("profile");
+
+        Daemon::new(config, MockBackend::new())
+    }
+
+    fn c(celsius: f64) -> Result<MilliCelsius, Error> {
+        Ok(MilliCelsius::from_celsius(celsius))
+    }
+
+    fn unreadable() -> Result<MilliCelsius, Error> {
+        Err(err!(NoReadings, "No temperatures"))
+    }
+
+    #[test]
+    fn tick_writes_only_on_change() {
+        let mut daemon = daemon();
+
+        for temp in [40.0, 45.0, 60.0, 62.0, 58.0, 76.0] {
+            daemon.tick(c(temp)).unwrap();
+        }
+
+        assert_eq!(
+            daemon.backend().writes(),
+            [FanSpeed::Auto, FanSpeed::Level(3), FanSpeed::Level(7)]
+        );
+        assert_eq!(daemon.level(), Some(FanSpeed::Level(7)));
+    }
+
+    #[test]
+    fn failsafe_level_while_unreadable() {
+        let mut daemon = daemon();
+
+        daemon.tick(c(62.0)).unwrap();
+        assert_eq!(daemon.tick(unreadable()).unwrap(), FanSpeed::FullSpeed);
+        assert_eq!(daemon.tick(unreadable()).unwrap(), FanSpeed::FullSpeed);
+        // back to the curve, from the bottom since full-speed isn't on it
+        assert_eq!(daemon.tick(c(58.0)).unwrap(), FanSpeed::Auto);
+
+        assert_eq!(
+            daemon.backend().writes(),
+            [FanSpeed::Level(3), FanSpeed::FullSpeed, FanSpeed::Auto]
+        );
+    }
+
+    #[test]
+    fn manual_level_beats_the_curve() {
+        let mut daemon = daemon();
+        daemon.tick(c(40.0)).unwrap();
+
+        set_level(&mut daemon, 5, None);
+
+        assert_eq!(daemon.tick(c(80.0)).unwrap(), FanSpeed::Level(5));
+        assert_eq!(daemon.tick(unreadable()).unwrap(), FanSpeed::Level(5));
+        assert_eq!(
+            daemon.backend().writes(),
+            [FanSpeed::Auto, FanSpeed::Level(5)]
+        );
+    }
+
+    fn set_level(daemon: &mut Daemon<MockBackend>, level: u8, duration: Option<u64>) {
+        let res = daemon.handle(Request::SetLevel {
+            level: FanSpeed::Level(level),
+            fan: None,
+            duration,
+        });
+        assert!(matches!(res, Response::Ok));
+    }
+
+    #[test]
+    fn timed_override_goes_back_to_what_was_before() {
+        let mut daemon = daemon();
+
+        // to the curve
+        set_level(&mut daemon, 7, Some(0));
+        daemon.expire_override();
+        assert_eq!(daemon.control(), ControlMode::Curve);
+
+        // to a level set before, also through another timed override
+        set_level(&mut daemon, 5, None);
+        set_level(&mut daemon, 6, Some(60));
+        set_level(&mut daemon, 7, Some(0));
+        assert!(daemon.expires().is_some());
+        daemon.expire_override();
+
+        let level = FanSpeed::Level(5);
+        assert_eq!(daemon.control(), ControlMode::Manual { level });
+        assert_eq!(daemon.expires(), None);
+        assert_eq!(daemon.tick(c(40.0)).unwrap(), level);
+    }
+
+    #[test]
+    fn restore_hands_back_to_auto() {
+        let mut daemon = daemon();
+        daemon.tick(c(80.0)).unwrap();
+
+        daemon.restore().unwrap();
+
+        assert_eq!(daemon.backend().last_write(), Some(FanSpeed::Auto));
+        assert_eq!(daemon.level(), Some(FanSpeed::Auto));
+        // already there for the curve, so the next tick leaves it alone
+        daemon.tick(c(40.0)).unwrap();
+        assert_eq!(
+            daemon.backend().writes(),
+            [FanSpeed::Level(7), FanSpeed::Auto]
+        );
+    }
+
+    #[test]
+    fn failsafe_beats_a_manual_level() {
+        let mut daemon = daemon();
+        set_level(&mut daemon, 2, None);
+
+        // a max of 100°C, tripping 5°C below it
+        let temps = |temp: f64| {
+            libtpfs::get_temps_from_sensors_output(format!(
+                r#"{{"coretemp-isa-0000": {{"Core 0": {{"temp2_input": {temp}, "temp2_max": 100.0}}}}}}"#
+            ))
+            .unwrap()
+        };
+
+        daemon.check_overheat(&temps(97.0));
+        assert_eq!(daemon.tick(c(97.0)).unwrap(), FanSpeed::FullSpeed);
+        assert_eq!(
+            daemon.control(),
+            ControlMode::Manual {
+                level: FanSpeed::Level(2)
+            }
+        );
+
+        daemon.check_overheat(&temps(80.0));
+        assert_eq!(daemon.tick(c(80.0)).unwrap(), FanSpeed::Level(2));
+        assert_eq!(
+            daemon.backend().writes(),
+            [FanSpeed::Level(2), FanSpeed::FullSpeed, FanSpeed::Level(2)]
+        );
+    }
+
+    #[test]
+    fn snapshots_carry_the_last_reading() {
+        let mut daemon = daemon();
+        let snapshots = daemon.subscribe();
+        let temps = libtpfs::get_temps_from_sensors_output(
+            r#"{"coretemp-isa-0000": {"Package id 0": {"temp1_input": 61.5}}}"#.to_string(),
+        )
+        .unwrap();
+
+        let temp = daemon.take_reading(Ok(temps));
+        assert_eq!(temp.as_ref().ok(), Some(&MilliCelsius(61_500)));
+        daemon.tick(temp).unwrap();
+        daemon.publish();
+
+        let snapshot = snapshots.try_recv().unwrap();
+        assert_eq!(snapshot.status.temp, Some(MilliCelsius(61_500)));
+        assert_eq!(snapshot.status.fan.level, FanSpeed::Level(3));
+        let package = &snapshot.temps.unwrap().packages[&0];
+        assert_eq!(package.temp, Some(MilliCelsius(61_500)));
+
+        // a failed reading leaves none to show
+        let temp = daemon.take_reading(Err(err!(MissingSensorChip, "No sensors")));
+        assert_eq!(temp.unwrap_err().kind(), &ErrorKind::MissingSensorChip);
+        daemon.tick(unreadable()).unwrap();
+        daemon.publish();
+        assert!(snapshots.try_recv().unwrap().temps.is_none());
+    }
+
+    #[test]
+    fn a_manual_level_forgets_the_profile() {
+        let mut daemon = daemon();
+        let state = daemon.config().profile_state.clone();
+
+        let res = daemon.handle(Request::SetProfile {
+            name: "quiet".to_string(),
+        });
+        assert!(matches!(res, Response::Ok), "{res:?}");
+        assert_eq!(daemon.profile(), Some("quiet"));
+        assert_eq!(
+            libtpfs::profile::load_active(&state).unwrap(),
+            Some("quiet".to_string())
+        );
+
+        // only for a while: the profile is still there afterwards
+        set_level(&mut daemon, 5, Some(60));
+        assert_eq!(daemon.profile(), Some("quiet"));
+        assert!(state.exists());
+
+        set_level(&mut daemon, 5, None);
+        assert_eq!(daemon.profile(), None);
+        assert!(!state.exists());
+    }
+}
@@ -0,0 +1,77 @@
+use clap::Parser;
+use libtpfanspeed as libtpfs;
+use libtpfs::{backend::ProcfsBackend, config::Config};
+use std::{path::PathBuf, sync::mpsc};
+use tpfand::{dbus, metrics, server::Server, Daemon, Event};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "A ThinkPad fan control daemon.", long_about = None)]
+struct Args {
+    #[arg(
+        short,
+        long,
+        default_value = libtpfs::config::DEFAULT_CONFIG_PATH,
+        help = "the config file to use."
+    )]
+    config: PathBuf,
+}
+
+fn fail(err: libtpfs::error::Error) -> ! {
+    eprintln!("ERROR: {err}");
+
+    if let Some(help) = err.help() {
+        eprintln!("HELP: {help}");
+    }
+
+    std::process::exit(err.kind().exit_code());
+}
+
+/// Like [`fail`], but remove the socket first, as exiting doesn't run destructors.
+fn fail_serving(server: Server, err: libtpfs::error::Error) -> ! {
+    drop(server);
+    fail(err)
+}
+
+fn main() {
+    let args = Args::parse();
+    let config = Config::load(&args.config).unwrap_or_else(|e| fail(e));
+
+    let (tx, rx) = mpsc::channel();
+    let server = Server::spawn(&config.socket, tx.clone()).unwrap_or_else(|e| fail(e));
+
+    let (dbus, metrics) = (config.dbus.clone(), config.metrics);
+    let mut daemon = Daemon::new(config, ProcfsBackend::default());
+
+    // the profile from before the last shutdown, see `tpfanctl profile set`
+    match libtpfs::profile::load_active(&daemon.config().profile_state) {
+        Ok(Some(name)) => match daemon.set_profile(&name) {
+            Ok(()) => eprintln!("Using the {name} profile"),
+            Err(e) => eprintln!("{e}, using the curve"),
+        },
+        Ok(None) => (),
+        Err(e) => eprintln!("{e}, using the curve"),
+    }
+
+    let _dbus = match dbus
+        .map(|bus| dbus::spawn(&bus, tx.clone(), daemon.subscribe()))
+        .transpose()
+    {
+        Ok(dbus) => dbus,
+        Err(e) => fail_serving(server, e),
+    };
+
+    if let Some(addr) = metrics {
+        if let Err(e) = metrics::spawn(addr, daemon.subscribe()) {
+            fail_serving(server, e);
+        }
+    }
+
+    ctrlc::set_handler(move || {
+        let _ = tx.send(Event::Shutdown);
+    })
+    .expect("Error setting SIGINT/SIGTERM handler");
+
+    if let Err(e) = daemon.run(&rx) {
+        fail_serving(server, e);
+    }
+}
@@ -0,0 +1,466 @@
+//! Serves the daemon's [`Snapshot`]s as Prometheus metrics on `/metrics`.
+
+use crate::Snapshot;
+use libtpfanspeed as libtpfs;
+use libtpfs::{error::*, FanSpeed};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Receiver,
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The most read of a request (line and headers), the rest is ignored.
+const MAX_REQUEST: u64 = 8 * 1024;
+
+/// Scrapes served at once, any more get a `503`.
+const MAX_CLIENTS: usize = 8;
+
+/// Every level the EC knows, so each one shows up even before it is used.
+const LEVELS: [FanSpeed; 11] = [
+    FanSpeed::Auto,
+    FanSpeed::Level(0),
+    FanSpeed::Level(1),
+    FanSpeed::Level(2),
+    FanSpeed::Level(3),
+    FanSpeed::Level(4),
+    FanSpeed::Level(5),
+    FanSpeed::Level(6),
+    FanSpeed::Level(7),
+    FanSpeed::FullSpeed,
+    FanSpeed::Disengaged,
+];
+
+#[derive(Default)]
+struct State {
+    snapshot: Option<Snapshot>,
+    /// When the last snapshot came in.
+    updated: Option<Instant>,
+    /// Seconds spent at each level, up to the last snapshot.
+    level_seconds: BTreeMap<String, f64>,
+}
+
+/// The levels the fan may be at when it reads back `level`. thinkpad_acpi reads
+/// `full-speed` back as `disengaged`, so both are current when either is.
+fn current_levels(level: FanSpeed) -> impl Iterator<Item = FanSpeed> {
+    LEVELS.into_iter().filter(move |l| l.is_same_as(level))
+}
+
+impl State {
+    fn update(&mut self, snapshot: Snapshot) {
+        let now = Instant::now();
+
+        if let (Some(prev), Some(updated)) = (&self.snapshot, self.updated) {
+            let seconds = now.duration_since(updated).as_secs_f64();
+            for level in current_levels(prev.status.fan.level) {
+                *self.level_seconds.entry(level.to_string()).or_default() += seconds;
+            }
+        }
+
+        self.snapshot = Some(snapshot);
+        self.updated = Some(now);
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let Some(snapshot) = &self.snapshot else {
+            return out;
+        };
+
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+
+        if let Some(temps) = &snapshot.temps {
+            let packages = temps
+                .packages
+                .iter()
+                .filter_map(|(id, package)| {
+                    Some((format!("{{package=\"{id}\"}}"), package.temp?.as_celsius()))
+                })
+                .collect();
+            metric(
+                "tpfand_package_temperature_celsius",
+                "gauge",
+                "CPU package temperature.",
+                packages,
+            );
+
+            let cores = temps
+                .cores()
+                .map(|(package, core, temp)| {
+                    (
+                        format!(
+                            "{{package=\"{package}\",core=\"{core}\",sensor=\"{}\",type=\"{}\"}}",
+                            escape(&temp.label),
+                            temp.core_type
+                        ),
+                        temp.temp.as_celsius(),
+                    )
+                })
+                .collect();
+            metric(
+                "tpfand_core_temperature_celsius",
+                "gauge",
+                "CPU core temperature.",
+                cores,
+            );
+
+            let dies = temps
+                .packages
+                .iter()
+                .flat_map(|(package, readings)| {
+                    readings.dies.iter().map(move |(die, temp)| {
+                        (
+                            format!(
+                                "{{package=\"{package}\",die=\"{die}\",sensor=\"{}\"}}",
+                                escape(&temp.label)
+                            ),
+                            temp.temp.as_celsius(),
+                        )
+                    })
+                })
+                .collect();
+            metric(
+                "tpfand_die_temperature_celsius",
+                "gauge",
+                "CPU die (CCD) temperature, on AMD CPUs.",
+                dies,
+            );
+        }
+
+        if let Some(temp) = snapshot.status.temp {
+            metric(
+                "tpfand_curve_temperature_celsius",
+                "gauge",
+                "The temperature the fan curve is evaluated against.",
+                vec![(String::new(), temp.as_celsius())],
+            );
+        }
+
+        let fans = snapshot
+            .fans
+            .iter()
+            .map(|fan| (format!("{{fan=\"{}\"}}", fan.id), fan.rpm as f64))
+            .collect();
+        metric("tpfand_fan_rpm", "gauge", "Fan speed.", fans);
+
+        let level = snapshot.status.fan.level;
+        let levels = LEVELS
+            .iter()
+            .map(|l| {
+                (
+                    format!("{{level=\"{l}\"}}"),
+                    l.is_same_as(level) as u8 as f64,
+                )
+            })
+            .collect();
+        metric(
+            "tpfand_fan_level",
+            "gauge",
+            "1 for the current fan level, 0 for the others.",
+            levels,
+        );
+
+        // count the time since the last snapshot too, to keep the counter moving
+        let since_update = self.updated.map_or(0.0, |t| t.elapsed().as_secs_f64());
+        let seconds = LEVELS
+            .iter()
+            .map(|l| {
+                let mut seconds = self
+                    .level_seconds
+                    .get(&l.to_string())
+                    .copied()
+                    .unwrap_or_default();
+                if l.is_same_as(level) {
+                    seconds += since_update;
+                }
+                (format!("{{level=\"{l}\"}}"), seconds)
+            })
+            .collect();
+        metric(
+            "tpfand_fan_level_seconds_total",
+            "counter",
+            "Time spent at each fan level since tpfand started.",
+            seconds,
+        );
+
+        let manual = matches!(
+            snapshot.status.control,
+            libtpfs::ipc::ControlMode::Manual { .. }
+        );
+        metric(
+            "tpfand_manual_override",
+            "gauge",
+            "1 while the curve is overridden by a manual level.",
+            vec![(String::new(), manual as u8 as f64)],
+        );
+
+        metric(
+            "tpfand_overheat_failsafe",
+            "gauge",
+            "1 while a core is too close to its limits and the fan is forced up.",
+            vec![(
+                String::new(),
+                snapshot.status.failsafe.is_some() as u8 as f64,
+            )],
+        );
+
+        out
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `/metrics` on `addr`, kept up to date from `snapshots`, in background threads.
+pub fn spawn(addr: SocketAddr, snapshots: Receiver<Snapshot>) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        err!(
+            GenericError,
+            "Is something else listening on it?",
+            "Can't serve metrics on {}: {}",
+            addr,
+            e
+        )
+    })?;
+
+    let state = Arc::new(Mutex::new(State::default()));
+
+    let updater = Arc::clone(&state);
+    thread::spawn(move || {
+        for snapshot in snapshots {
+            if let Ok(mut state) = updater.lock() {
+                state.update(snapshot);
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let clients = Arc::new(AtomicUsize::new(0));
+
+        for stream in listener.incoming().flatten() {
+            if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                clients.fetch_sub(1, Ordering::SeqCst);
+                let _ = stream.set_write_timeout(Some(TIMEOUT)).and_then(|_| {
+                    respond(
+                        &stream,
+                        "503 Service Unavailable",
+                        "text/plain",
+                        "Too busy\n",
+                    )
+                });
+                continue;
+            }
+
+            let (state, clients) = (Arc::clone(&state), Arc::clone(&clients));
+            thread::spawn(move || {
+                if let Err(e) = handle(stream, &state) {
+                    eprintln!("Metrics client error: {e}");
+                }
+                clients.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // skip the headers, closing with unread data would reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    match request.split_whitespace().nth(1).unwrap_or_default() {
+        "/metrics" => {
+            let body = state.lock().map(|state| state.render()).unwrap_or_default();
+            respond(&stream, "200 OK", "text/plain; version=0.0.4", &body)
+        }
+        _ => respond(
+            &stream,
+            "404 Not Found",
+            "text/plain",
+            "Not found, try /metrics\n",
+        ),
+    }
+}
+
+fn respond(
+    mut stream: &TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtpfs::{
+        ipc::{ControlMode, DaemonStatus},
+        FanReading, FanStatus, MilliCelsius,
+    };
+
+    fn snapshot(level: FanSpeed) -> Snapshot {
+        let sensors = r#"{
+            "coretemp-isa-0000": {
+                "Package id 0": { "temp1_input": 61.5 },
+                "Core 0": { "temp2_input": 55.0 },
+                "Core 1": { "temp3_input": 70.0 }
+            }
+        }"#;
+
+        Snapshot {
+            status: DaemonStatus {
+                fan: FanStatus {
+                    enabled: true,
+                    speed: 2900,
+                    level,
+                    commands: Vec::new(),
+                    watchdog: None,
+                },
+                control: ControlMode::Manual { level },
+                temp: Some(MilliCelsius::from_celsius(61.5)),
+                failsafe: None,
+                expires_in: None,
+                after_expiry: None,
+                profile: None,
+            },
+            temps: Some(libtpfs::get_temps_from_sensors_output(sensors.to_string()).unwrap()),
+            fans: vec![FanReading { id: 1, rpm: 2900 }],
+        }
+    }
+
+    #[test]
+    fn nothing_before_the_first_snapshot() {
+        assert_eq!(State::default().render(), "");
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        assert_eq!(escape("Core \"1\"\n\\"), r#"Core \"1\"\n\\"#);
+    }
+
+    #[test]
+    fn render() {
+        let mut state = State::default();
+        state.update(snapshot(FanSpeed::Level(3)));
+        let metrics = state.render();
+        let lines: Vec<&str> = metrics.lines().collect();
+
+        for expected in [
+            "# HELP tpfand_package_temperature_celsius CPU package temperature.",
+            "# TYPE tpfand_package_temperature_celsius gauge",
+            r#"tpfand_package_temperature_celsius{package="0"} 61.5"#,
+            r#"tpfand_core_temperature_celsius{package="0",core="0",sensor="Core 0",type="unknown"} 55"#,
+            "tpfand_curve_temperature_celsius 61.5",
+            r#"tpfand_fan_rpm{fan="1"} 2900"#,
+            r#"tpfand_fan_level{level="3"} 1"#,
+            r#"tpfand_fan_level{level="auto"} 0"#,
+            "# TYPE tpfand_fan_level_seconds_total counter",
+            "tpfand_manual_override 1",
+            "tpfand_overheat_failsafe 0",
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "{expected} is missing from\n{metrics}"
+            );
+        }
+
+        // every sample is `name{labels} value`, under its own HELP and TYPE
+        let mut documented = Vec::new();
+        for line in &lines {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                documented.push(help.split(' ').next().unwrap());
+                continue;
+            }
+            if line.starts_with("# TYPE ") {
+                continue;
+            }
+
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            let name = series.split('{').next().unwrap();
+            assert_eq!(documented.last(), Some(&name), "{line}");
+            assert!(value.parse::<f64>().is_ok(), "{line}");
+        }
+    }
+
+    fn level_seconds(state: &State, level: &str) -> f64 {
+        let series = format!(r#"tpfand_fan_level_seconds_total{{level="{level}"}} "#);
+        let metrics = state.render();
+        let line = metrics.lines().find(|line| line.starts_with(&series));
+        line.unwrap()[series.len()..].parse().unwrap()
+    }
+
+    #[test]
+    fn level_seconds_accumulate() {
+        let mut state = State::default();
+        state.update(snapshot(FanSpeed::Level(3)));
+        thread::sleep(Duration::from_millis(20));
+        state.update(snapshot(FanSpeed::Level(3)));
+        let first = state.level_seconds["3"];
+        assert!(first >= 0.02, "{first}");
+
+        thread::sleep(Duration::from_millis(20));
+        state.update(snapshot(FanSpeed::Auto));
+        let second = state.level_seconds["3"];
+        assert!(second >= first + 0.02, "{second}");
+        assert!(!state.level_seconds.contains_key("auto"));
+
+        // the current level keeps counting between snapshots
+        assert!(level_seconds(&state, "3") == second);
+        thread::sleep(Duration::from_millis(5));
+        assert!(level_seconds(&state, "auto") > 0.0);
+    }
+
+    #[test]
+    fn full_speed_reads_back_as_disengaged() {
+        let mut state = State::default();
+        state.update(snapshot(FanSpeed::Disengaged));
+        thread::sleep(Duration::from_millis(20));
+        state.update(snapshot(FanSpeed::Disengaged));
+
+        let metrics = state.render();
+        let lines: Vec<&str> = metrics.lines().collect();
+        assert!(lines.contains(&r#"tpfand_fan_level{level="full-speed"} 1"#));
+        assert!(lines.contains(&r#"tpfand_fan_level{level="disengaged"} 1"#));
+        assert!(lines.contains(&r#"tpfand_fan_level{level="7"} 0"#));
+        assert_eq!(
+            state.level_seconds["full-speed"],
+            state.level_seconds["disengaged"]
+        );
+        assert!(state.level_seconds["full-speed"] >= 0.02);
+    }
+}
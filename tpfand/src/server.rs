@@ -0,0 +1,262 @@
+//! Accepts [`Request`]s on a Unix socket and hands them to the daemon loop.
+
+use crate::Event;
+use libtpfanspeed as libtpfs;
+use libtpfs::{
+    error::*,
+    ipc::{Request, Response},
+};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests are a line of JSON, far shorter than this.
+const MAX_REQUEST: u64 = 64 * 1024;
+
+/// Clients served at once, any more are turned away.
+const MAX_CLIENTS: usize = 16;
+
+/// The listening socket, removed again on drop.
+pub struct Server {
+    path: PathBuf,
+}
+
+impl Server {
+    /// Listen on `path` in a background thread, forwarding every request to `events`.
+    pub fn spawn(path: impl Into<PathBuf>, events: Sender<Event>) -> Result<Self, Error> {
+        let path = path.into();
+
+        // left behind by a daemon that didn't shut down cleanly
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                return Err(err!(
+                    Ipc,
+                    "Stop the other tpfand first",
+                    "Another tpfand is listening on {}",
+                    path.display()
+                ));
+            }
+
+            fs::remove_file(&path).map_err(|e| generic_err!(e))?;
+        }
+
+        let listener = UnixListener::bind(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::PermissionDenied => err!(
+                PermissionDenied,
+                "Do you have root permissions?",
+                "while trying to create {}",
+                path.display()
+            )
+            .with_source(e),
+            _ => generic_err!(e),
+        })?;
+
+        // anyone may ask, the daemon decides; hence the limits on what they can send
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666))
+            .map_err(|e| generic_err!(e))?;
+
+        thread::spawn(move || {
+            let clients = Arc::new(AtomicUsize::new(0));
+
+            for stream in listener.incoming().flatten() {
+                if clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                    let busy = err!(Ipc, "Try again in a moment", "tpfand is too busy");
+                    let _ = stream
+                        .set_write_timeout(Some(TIMEOUT))
+                        .and_then(|_| respond(&stream, &Response::Error(busy)));
+                    continue;
+                }
+
+                let events = events.clone();
+                let clients = clients.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle(stream, &events) {
+                        eprintln!("Client error: {e}");
+                    }
+                    clients.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        Ok(Self { path })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn handle(stream: UnixStream, events: &Sender<Event>) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut line = String::new();
+    BufReader::new((&stream).take(MAX_REQUEST)).read_line(&mut line)?;
+
+    // clients probing whether the daemon is running don't send anything
+    if line.trim().is_empty() {
+        return Ok(());
+    }
+
+    if !line.ends_with('\n') && line.len() as u64 >= MAX_REQUEST {
+        let e = err!(
+            Ipc,
+            "Requests are a single line of JSON",
+            "The request is longer than {} bytes",
+            MAX_REQUEST
+        );
+        return respond(&stream, &Response::Error(e));
+    }
+
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => {
+            let (tx, rx) = mpsc::channel();
+            events
+                .send(Event::Request(request, tx))
+                .ok()
+                .and_then(|_| rx.recv().ok())
+                .unwrap_or_else(|| {
+                    Response::Error(err!(Ipc, "tpfand is shutting down", "No response"))
+                })
+        }
+        Err(e) => Response::Error(
+            err!(
+                Ipc,
+                "Are tpfand and tpfanctl the same version?",
+                "Malformed request: {}",
+                e
+            )
+            .with_source(e),
+        ),
+    };
+
+    respond(&stream, &response)
+}
+
+fn respond(mut stream: &UnixStream, response: &Response) -> io::Result<()> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libtpfs::ipc::Client;
+    use std::path::Path;
+
+    /// A server on a socket of its own, answering every request with `Ok`
+    /// and passing it on to the returned receiver.
+    fn server(test: &str) -> (Server, mpsc::Receiver<Request>) {
+        let dir = std::env::temp_dir().join(format!("tpfand-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let (events, rx) = mpsc::channel();
+        let server = Server::spawn(dir.join("tpfand.sock"), events).unwrap();
+
+        let (requests, received) = mpsc::channel();
+        thread::spawn(move || {
+            for event in rx {
+                if let Event::Request(request, reply) = event {
+                    let _ = requests.send(request);
+                    let _ = reply.send(Response::Ok);
+                }
+            }
+        });
+
+        (server, received)
+    }
+
+    fn read_response(stream: &UnixStream) -> Response {
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    fn description(response: Response) -> String {
+        match response {
+            Response::Error(e) => e.desc().unwrap_or_default().to_string(),
+            response => panic!("expected an error, got {response:?}"),
+        }
+    }
+
+    #[test]
+    fn requests_reach_the_daemon() {
+        let (server, received) = server("requests");
+        let client = Client::connect(&server.path).unwrap();
+
+        client.resume().unwrap();
+        assert_eq!(received.recv().unwrap(), Request::Resume);
+
+        let mut stream = UnixStream::connect(&server.path).unwrap();
+        stream.write_all(b"{\"command\":\"reboot\"}\n").unwrap();
+        assert!(description(read_response(&stream)).starts_with("Malformed request"));
+    }
+
+    #[test]
+    fn requests_are_bounded() {
+        let (server, received) = server("bounded");
+
+        let mut stream = UnixStream::connect(&server.path).unwrap();
+        // no newline in sight; anything past the bound would go unread and
+        // reset the connection before the answer arrives
+        stream.write_all(&vec![b'x'; MAX_REQUEST as usize]).unwrap();
+        assert_eq!(
+            description(read_response(&stream)),
+            format!("The request is longer than {MAX_REQUEST} bytes")
+        );
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn clients_are_bounded() {
+        let (server, received) = server("clients");
+
+        // connected, but they never send anything
+        let idle: Vec<_> = (0..MAX_CLIENTS)
+            .map(|_| UnixStream::connect(&server.path).unwrap())
+            .collect();
+
+        let stream = UnixStream::connect(&server.path).unwrap();
+        assert_eq!(description(read_response(&stream)), "tpfand is too busy");
+
+        // and served again once they're gone
+        drop(idle);
+        let client = Client::new(&server.path);
+        let deadline = std::time::Instant::now() + TIMEOUT;
+        while client.resume().is_err() {
+            assert!(std::time::Instant::now() < deadline, "still too busy");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(received.recv().unwrap(), Request::Resume);
+    }
+
+    #[test]
+    fn the_socket_is_removed_on_drop() {
+        let (server, _) = server("drop");
+        let path = server.path.clone();
+
+        assert!(Path::new(&path).exists());
+        drop(server);
+        assert!(!Path::new(&path).exists());
+    }
+}
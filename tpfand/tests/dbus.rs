@@ -0,0 +1,153 @@
+//! Serves a [`MockBackend`] daemon on a private `dbus-daemon` and drives it
+//! like a desktop client would. Skipped if `dbus-daemon` isn't installed.
+
+use libtpfanspeed as libtpfs;
+use libtpfs::{backend::MockBackend, config::Config, FanSpeed};
+use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+};
+use tpfand::{dbus, Daemon, Event};
+use zbus::{
+    blocking::{
+        connection,
+        fdo::{PropertiesChangedIterator, PropertiesProxy},
+        proxy, Proxy,
+    },
+    proxy::CacheProperties,
+    zvariant::OwnedValue,
+};
+
+const INTERFACE: &str = "io.github.ezntek.tpfand.Fan1";
+
+/// A session bus of its own, stopped on drop.
+struct Bus {
+    daemon: Child,
+    address: String,
+}
+
+impl Bus {
+    fn start() -> Option<Self> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.as_mut()?)
+            .read_line(&mut address)
+            .ok()?;
+
+        Some(Self {
+            daemon,
+            address: address.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+fn config() -> Config {
+    Config::from_toml(
+        r#"
+        interval = 60
+
+        [curve]
+        base = "auto"
+        points = [{ speed = 7, rising = 75, falling = 70 }]
+        "#,
+    )
+    .unwrap()
+}
+
+/// The next `PropertiesChanged` of the fan interface with `name` in it.
+fn next_change(changes: &mut PropertiesChangedIterator, name: &str) -> OwnedValue {
+    for signal in changes {
+        let args = signal.args().unwrap();
+        if args.interface_name() != INTERFACE {
+            continue;
+        }
+        if let Some(value) = args.changed_properties().get(name) {
+            return value.try_to_owned().unwrap();
+        }
+    }
+
+    panic!("The signals stopped before {name} changed");
+}
+
+#[test]
+fn set_level_and_resume() {
+    let Some(bus) = Bus::start() else {
+        eprintln!("dbus-daemon isn't installed, skipping");
+        return;
+    };
+
+    let mut daemon = Daemon::new(config(), MockBackend::new());
+    let (events, rx) = mpsc::channel();
+    let _service = dbus::spawn(&bus.address, events.clone(), daemon.subscribe()).unwrap();
+
+    let conn = connection::Builder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let fan: Proxy = proxy::Builder::new(&conn)
+        .destination(dbus::BUS_NAME)
+        .unwrap()
+        .path(dbus::OBJECT_PATH)
+        .unwrap()
+        .interface(INTERFACE)
+        .unwrap()
+        .cache_properties(CacheProperties::No)
+        .build()
+        .unwrap();
+    let properties = PropertiesProxy::builder(&conn)
+        .destination(dbus::BUS_NAME)
+        .unwrap()
+        .path(dbus::OBJECT_PATH)
+        .unwrap()
+        .build()
+        .unwrap();
+    let mut changes = properties.receive_properties_changed().unwrap();
+
+    let running = thread::spawn(move || {
+        let res = daemon.run(&rx);
+        (daemon, res)
+    });
+
+    // the first reading
+    let mode: String = next_change(&mut changes, "ControlMode").try_into().unwrap();
+    assert_eq!(mode, "curve");
+
+    fan.call_method("SetLevel", &("3",)).unwrap();
+    let level: String = next_change(&mut changes, "Level").try_into().unwrap();
+    assert_eq!(level, "3");
+    let mode: String = next_change(&mut changes, "ControlMode").try_into().unwrap();
+    assert_eq!(mode, "manual");
+    assert_eq!(fan.get_property::<String>("Level").unwrap(), "3");
+    assert_eq!(fan.get_property::<String>("ControlMode").unwrap(), "manual");
+
+    fan.call_method("Resume", &()).unwrap();
+    let mode: String = next_change(&mut changes, "ControlMode").try_into().unwrap();
+    assert_eq!(mode, "curve");
+    assert_eq!(fan.get_property::<String>("ControlMode").unwrap(), "curve");
+
+    // bad levels are turned down before they reach the daemon
+    let e = fan.call_method("SetLevel", &("9",)).unwrap_err();
+    assert!(
+        matches!(&e, zbus::Error::MethodError(name, ..) if name.as_str() == "org.freedesktop.DBus.Error.InvalidArgs"),
+        "{e}"
+    );
+
+    events.send(Event::Shutdown).unwrap();
+    let (daemon, res) = running.join().unwrap();
+    res.unwrap();
+    assert_eq!(daemon.backend().last_write(), Some(FanSpeed::Auto));
+}